@@ -6,7 +6,7 @@ use clap::Parser;
 use process_tree::check_tree_size;
 use robotmk::config::RetryStrategy;
 use robotmk::environment::{Environment, RCCEnvironment, SystemEnvironment};
-use robotmk::plans::run_attempts_with_rebot;
+use robotmk::plans::{run_attempts_with_rebot, AttemptsSpec};
 use robotmk::rf::robot::Robot;
 use robotmk::session::{CurrentSession, RunSpec, Session};
 use std::env::var;
@@ -51,15 +51,18 @@ fn system_main() -> AnyhowResult<()> {
     let token = CancellationToken::new();
     let thread_token = token.clone();
     let running = thread::spawn(move || {
-        run_attempts_with_rebot(
-            &robot,
-            "test",
-            &Environment::System(SystemEnvironment {}),
-            &Session::Current(CurrentSession {}),
-            3,
-            &thread_token,
-            &test_dir,
-        )
+        run_attempts_with_rebot(&AttemptsSpec {
+            id: "test",
+            robot: &robot,
+            environment: &Environment::System(SystemEnvironment {}),
+            session: &Session::Current(CurrentSession {}),
+            timeout: 3,
+            resource_limits_config: None,
+            termination_config: None,
+            orphan_process_policy: None,
+            cancellation_token: &thread_token,
+            output_directory: &test_dir,
+        })
     });
     while !flag_file.exists() {
         // Wait for all children to be created
@@ -116,6 +119,8 @@ fn rcc_main(rcc_binary_path: Utf8PathBuf) -> AnyhowResult<()> {
         command_spec: &build_instructions.command_spec,
         base_path: &test_dir,
         timeout: build_instructions.timeout,
+        resource_limits_config: None,
+        termination_config: None,
         cancellation_token: &CancellationToken::new(),
     };
     session.run(&run_spec).unwrap();
@@ -123,15 +128,18 @@ fn rcc_main(rcc_binary_path: Utf8PathBuf) -> AnyhowResult<()> {
     let token = CancellationToken::new();
     let thread_token = token.clone();
     let running = thread::spawn(move || {
-        run_attempts_with_rebot(
-            &robot,
-            "test",
-            &rcc_environment,
-            &session,
-            20,
-            &thread_token,
-            &test_dir,
-        )
+        run_attempts_with_rebot(&AttemptsSpec {
+            id: "test",
+            robot: &robot,
+            environment: &rcc_environment,
+            session: &session,
+            timeout: 20,
+            resource_limits_config: None,
+            termination_config: None,
+            orphan_process_policy: None,
+            cancellation_token: &thread_token,
+            output_directory: &test_dir,
+        })
     });
     while !flag_file.exists() {
         // Wait for all children to be created
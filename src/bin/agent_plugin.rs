@@ -1,21 +1,90 @@
-use camino::Utf8PathBuf;
-use clap::Parser;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
 use robotmk::{
-    config::Config,
-    lock::Locker,
-    results::ConfigSection,
-    section::{read, Host, Section},
+    config::{Config, SectionCompression},
+    lock::{Locker, LockerError},
+    nagios::{check_plan, CheckResult},
+    results::{
+        plan_results_directory, results_directory_lock_path, ConfigSection, DeliveryStateStatus,
+        PlanExecutionReport, ResultsReadingStatus, SectionReadErrors, StalenessReport,
+    },
+    section::{read, read_section, Host, ReadSections, Section, WriteSection},
+    staleness::staleness_report,
 };
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::env::{var, VarError};
-use std::fs::read_to_string;
-use std::io;
+use std::fs::{create_dir_all, read_to_string};
+use std::io::{self, Write};
+use std::process::exit;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+// The agent must never block indefinitely, even if a lock holder is stuck.
+const READ_LOCK_TIMEOUT: Duration = Duration::from_secs(20);
 
 #[derive(Parser)]
 #[command(about = "Robotmk agent plugin.", version)]
 struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Configuration file path.
     #[clap(name = "CONFIG_PATH")]
     pub config_path: Option<Utf8PathBuf>,
+
+    /// Only emit plan execution reports which have not yet been delivered to this consumer.
+    /// Status sections are always emitted.
+    #[clap(long, value_parser = parse_consumer)]
+    pub incremental: Option<String>,
+
+    /// Directory in which the delivery state of the incremental mode is kept. Defaults to the
+    /// variable data directory of the agent (MK_VARDIR or MK_STATEDIR).
+    #[clap(long)]
+    pub state_directory: Option<Utf8PathBuf>,
+
+    /// Output format.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Checkmk)]
+    pub format: OutputFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Summarize the latest results of a plan as a Nagios-compatible check.
+    Check {
+        /// Plan ID.
+        #[clap(name = "PLAN_ID")]
+        plan_id: String,
+
+        /// Configuration file path.
+        #[clap(name = "CONFIG_PATH")]
+        config_path: Option<Utf8PathBuf>,
+    },
+}
+
+#[derive(Clone, ValueEnum)]
+enum OutputFormat {
+    /// Checkmk agent sections, with piggyback framing
+    Checkmk,
+    /// Single JSON document, sections grouped by host
+    Json,
+    /// One JSON object per section and line
+    Ndjson,
+}
+
+// The consumer names the file of its delivery state, so it must not contain path separators or
+// `..`.
+fn parse_consumer(consumer: &str) -> Result<String, String> {
+    if !consumer.is_empty()
+        && consumer
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(consumer.into())
+    } else {
+        Err("Consumer may only contain ASCII letters, digits, `-` and `_`".into())
+    }
 }
 
 fn determine_config_path(arg: Option<Utf8PathBuf>) -> Result<Utf8PathBuf, String> {
@@ -34,50 +103,387 @@ fn config_path_from_env() -> Result<Utf8PathBuf, String> {
     Ok(Utf8PathBuf::from(config_dir).join("robotmk.json"))
 }
 
-fn report_config_section(section: &ConfigSection) {
-    let section_serialized =
-        serde_json::to_string(section).expect("Unexpected serialization error: ConfigSection");
-    println!("<<<robotmk_config_v2:sep(0)>>>");
-    println!("{section_serialized}");
+fn read_config(config_path: &Utf8Path) -> Result<(String, Config), String> {
+    let raw = read_to_string(config_path)
+        .map_err(|e| format!("Error while reading {config_path}: {e}"))?;
+    let config = serde_json::from_str(&raw)
+        .map_err(|e| format!("Error while reading {config_path}: {e}"))?;
+    Ok((raw, config))
+}
+
+fn config_section(section: &ConfigSection) -> Section {
+    Section::new(
+        "robotmk_config_v2",
+        &serde_json::to_string(section).expect("Unexpected serialization error: ConfigSection"),
+        Host::Source,
+        None,
+    )
+}
+
+fn staleness_section(report: &StalenessReport) -> Section {
+    Section::new(
+        "robotmk_staleness",
+        &serde_json::to_string(report).expect("Unexpected serialization error: StalenessReport"),
+        Host::Source,
+        None,
+    )
 }
 
-fn print_sections(sections: &[Section], stdout: &mut impl io::Write) {
+fn print_sections(
+    sections: &[Section],
+    format: &OutputFormat,
+    stdout: &mut impl io::Write,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Checkmk => print_checkmk_sections(sections, stdout),
+        OutputFormat::Json => print_json_document(sections, stdout),
+        OutputFormat::Ndjson => print_ndjson_sections(sections, stdout),
+    }
+}
+
+fn print_checkmk_sections(sections: &[Section], stdout: &mut impl io::Write) -> io::Result<()> {
     // TODO: Test this function.
     for section in sections.iter() {
-        let mut with_header = format!("<<<{}:sep(0)>>>\n{}\n", section.name, section.content);
+        let mut with_header = format!(
+            "<<<{}:sep(0)>>>\n{}\n",
+            section.name,
+            printable_content(section)
+        );
         if let Host::Piggyback(host) = &section.host {
             with_header = format!("<<<<{}>>>>\n{}<<<<>>>>\n", host, with_header);
         }
-        write!(stdout, "{}", with_header).unwrap();
+        write!(stdout, "{}", with_header)?;
     }
+    Ok(())
 }
 
-fn main() {
-    let arguments = Args::parse();
+// Compressed content is wrapped together with the compression marker, such that the consumer can
+// tell it apart from plain JSON content.
+fn printable_content(section: &Section) -> String {
+    match &section.compression {
+        Some(compression) => serde_json::to_string(&serde_json::json!({
+            "compression": compression,
+            "content": section.content,
+        }))
+        .expect("Unexpected serialization error: compressed section"),
+        None => section.content.clone(),
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSection<'a> {
+    name: &'a str,
+    content: Value,
+    compression: &'a Option<SectionCompression>,
+}
+
+impl<'a> From<&'a Section> for JsonSection<'a> {
+    // Uncompressed content is embedded as JSON, such that consumers do not need to parse twice.
+    fn from(section: &'a Section) -> Self {
+        Self {
+            name: &section.name,
+            content: match section.compression {
+                Some(_) => Value::String(section.content.clone()),
+                None => serde_json::from_str(&section.content)
+                    .unwrap_or_else(|_| Value::String(section.content.clone())),
+            },
+            compression: &section.compression,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonDocument<'a> {
+    source: Vec<JsonSection<'a>>,
+    piggyback: BTreeMap<&'a str, Vec<JsonSection<'a>>>,
+}
+
+#[derive(Serialize)]
+struct NdjsonSection<'a> {
+    host: &'a Host,
+    #[serde(flatten)]
+    section: JsonSection<'a>,
+}
+
+fn print_json_document(sections: &[Section], stdout: &mut impl io::Write) -> io::Result<()> {
+    let mut document = JsonDocument {
+        source: vec![],
+        piggyback: BTreeMap::new(),
+    };
+    for section in sections {
+        match &section.host {
+            Host::Source => document.source.push(section.into()),
+            Host::Piggyback(host) => document
+                .piggyback
+                .entry(host)
+                .or_default()
+                .push(section.into()),
+        }
+    }
+    writeln!(
+        stdout,
+        "{}",
+        serde_json::to_string(&document).expect("Unexpected serialization error: JsonDocument")
+    )
+}
+
+fn print_ndjson_sections(sections: &[Section], stdout: &mut impl io::Write) -> io::Result<()> {
+    for section in sections {
+        writeln!(
+            stdout,
+            "{}",
+            serde_json::to_string(&NdjsonSection {
+                host: &section.host,
+                section: section.into(),
+            })
+            .expect("Unexpected serialization error: NdjsonSection")
+        )?;
+    }
+    Ok(())
+}
+
+fn determine_state_directory(arg: Option<Utf8PathBuf>) -> Option<Utf8PathBuf> {
+    arg.or_else(|| {
+        ["MK_VARDIR", "MK_STATEDIR"]
+            .into_iter()
+            .find_map(|name| var(name).ok())
+            .map(Utf8PathBuf::from)
+    })
+}
+
+// `consumer` has been validated by `parse_consumer`.
+fn delivery_state_path(state_directory: &Utf8Path, consumer: &str) -> Utf8PathBuf {
+    state_directory
+        .join("robotmk_delivery_state")
+        .join(format!("{consumer}.json"))
+}
+
+// Maps the paths of versioned sections to the version which was last delivered.
+fn read_delivery_state(path: &Utf8Path) -> HashMap<Utf8PathBuf, i64> {
+    read_to_string(path)
+        .ok()
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_default()
+}
+
+// The new state is only put in place once the sections have been printed, see `commit`.
+// Each run writes to its own temporary file, such that concurrent runs for the same consumer do not
+// interfere. The last one to commit wins.
+struct PendingDeliveryState {
+    file: NamedTempFile,
+    path: Utf8PathBuf,
+}
+
+impl PendingDeliveryState {
+    fn write(path: &Utf8Path, state: &HashMap<Utf8PathBuf, i64>) -> io::Result<Self> {
+        let directory = path.parent().unwrap_or(Utf8Path::new("."));
+        create_dir_all(directory)?;
+        let mut file = NamedTempFile::new_in(directory)?;
+        file.write_all(serde_json::to_string(state)?.as_bytes())?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    fn commit(self) -> io::Result<()> {
+        self.file
+            .persist(self.path)
+            .map(|_| ())
+            .map_err(|e| e.error)
+    }
+}
+
+fn select_undelivered(
+    sections: Vec<(Utf8PathBuf, Section)>,
+    state_path: &Utf8Path,
+) -> (Vec<Section>, HashMap<Utf8PathBuf, i64>) {
+    let delivered = read_delivery_state(state_path);
+    let mut now_delivered = HashMap::new();
+    let mut selected = vec![];
+    for (path, section) in sections {
+        match section.version {
+            Some(version) => {
+                if delivered.get(&path) != Some(&version) {
+                    selected.push(section);
+                }
+                now_delivered.insert(path, version);
+            }
+            None => selected.push(section),
+        }
+    }
+    (selected, now_delivered)
+}
+
+// If the delivery state cannot be tracked, all sections are emitted.
+fn incremental_sections(
+    sections: Vec<(Utf8PathBuf, Section)>,
+    consumer: &str,
+    state_directory: Option<Utf8PathBuf>,
+) -> (Vec<Section>, Option<PendingDeliveryState>) {
+    let Some(state_directory) = state_directory else {
+        let mut selected = vec![DeliveryStateStatus::StateDirectoryUnknown.section()];
+        selected.extend(sections.into_iter().map(|(_, section)| section));
+        return (selected, None);
+    };
+    let state_path = delivery_state_path(&state_directory, consumer);
+    let (selected, now_delivered) = select_undelivered(sections, &state_path);
+    match PendingDeliveryState::write(&state_path, &now_delivered) {
+        Ok(pending_state) => (
+            [DeliveryStateStatus::Ok.section()]
+                .into_iter()
+                .chain(selected)
+                .collect(),
+            Some(pending_state),
+        ),
+        Err(error) => (
+            [DeliveryStateStatus::WriteFailed(format!("{state_path}: {error}")).section()]
+                .into_iter()
+                .chain(selected)
+                .collect(),
+            None,
+        ),
+    }
+}
+
+// Schedulers which do not create a dedicated lock file lock the configuration file instead.
+fn results_directory_locker(config: &Config, config_path: &Utf8Path) -> Locker {
+    let lock_path = results_directory_lock_path(&config.results_directory);
+    let lock_path = if lock_path.exists() {
+        lock_path.as_path()
+    } else {
+        config_path
+    };
+    Locker::new(lock_path, None).with_timeout(READ_LOCK_TIMEOUT)
+}
+
+fn read_results(
+    config: &Config,
+    config_path: &Utf8Path,
+) -> Result<ReadSections, ResultsReadingStatus> {
+    // The scheduler creates the working directory before the results directory during its setup.
+    if !config.results_directory.exists() {
+        return Err(if config.working_directory.exists() {
+            ResultsReadingStatus::ResultsDirectoryMissing(config.results_directory.clone())
+        } else {
+            ResultsReadingStatus::SchedulerNotStarted
+        });
+    }
+    read(
+        &config.results_directory,
+        &results_directory_locker(config, config_path),
+    )
+    .map_err(|error| {
+        let status = match error {
+            LockerError::Open(..) => ResultsReadingStatus::LockFileInaccessible,
+            LockerError::Timeout(..) => ResultsReadingStatus::LockTimedOut,
+            _ => ResultsReadingStatus::LockAcquisitionFailed,
+        };
+        status(format!("{:#}", anyhow::anyhow!(error)))
+    })
+}
+
+fn collect_sections(arguments: Args) -> (Vec<Section>, Option<PendingDeliveryState>) {
     let config_path = match determine_config_path(arguments.config_path) {
         Ok(p) => p,
-        Err(e) => {
-            report_config_section(&ConfigSection::ReadingError(e));
-            return;
-        }
+        Err(e) => return (vec![config_section(&ConfigSection::ReadingError(e))], None),
+    };
+    let (raw, config) = match read_config(&config_path) {
+        Ok(read) => read,
+        Err(e) => return (vec![config_section(&ConfigSection::ReadingError(e))], None),
     };
-    let raw = match read_to_string(&config_path) {
-        Ok(raw) => raw,
-        Err(e) => {
-            let message = format!("Error while reading {config_path}: {e}");
-            report_config_section(&ConfigSection::ReadingError(message));
-            return;
+    let mut sections = vec![config_section(&ConfigSection::FileContent(raw))];
+    let read_sections = match read_results(&config, &config_path) {
+        Ok(read_sections) => read_sections,
+        Err(status) => {
+            sections.push(status.section());
+            return (sections, None);
         }
     };
-    let config: Config = match serde_json::from_str(&raw) {
-        Ok(config) => config,
-        Err(e) => {
-            let message = format!("Error while reading {config_path}: {e}");
-            report_config_section(&ConfigSection::ReadingError(message));
-            return;
+    sections.push(ResultsReadingStatus::Ok.section());
+    sections.push(staleness_section(&staleness_report(
+        &config,
+        &read_sections.sections,
+        chrono::Utc::now().timestamp(),
+    )));
+    sections.push(SectionReadErrors(read_sections.errors).section());
+    match arguments.incremental {
+        Some(consumer) => {
+            let (selected, pending_state) = incremental_sections(
+                read_sections.sections,
+                &consumer,
+                determine_state_directory(arguments.state_directory),
+            );
+            sections.extend(selected);
+            (sections, pending_state)
         }
+        None => {
+            sections.extend(
+                read_sections
+                    .sections
+                    .into_iter()
+                    .map(|(_, section)| section),
+            );
+            (sections, None)
+        }
+    }
+}
+
+fn read_report(
+    report_path: &Utf8Path,
+    config: &Config,
+    config_path: &Utf8Path,
+) -> anyhow::Result<Option<PlanExecutionReport>> {
+    let Some(section) = read_section(report_path, &results_directory_locker(config, config_path))?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_str(
+        &section.decompressed_content()?,
+    )?))
+}
+
+fn check(plan_id: &str, config_path: Option<Utf8PathBuf>) -> CheckResult {
+    let config_path = match determine_config_path(config_path) {
+        Ok(p) => p,
+        Err(e) => return CheckResult::unknown(e),
     };
-    report_config_section(&ConfigSection::FileContent(raw));
-    let sections = read(config.results_directory, &Locker::new(&config_path, None)).unwrap();
-    print_sections(&sections, &mut io::stdout());
+    let config = match read_config(&config_path) {
+        Ok((_, config)) => config,
+        Err(e) => return CheckResult::unknown(e),
+    };
+    let report_path =
+        plan_results_directory(&config.results_directory).join(format!("{plan_id}.json"));
+    match read_report(&report_path, &config, &config_path) {
+        Ok(report) => check_plan(
+            &config,
+            plan_id,
+            report.as_ref(),
+            chrono::Utc::now().timestamp(),
+        ),
+        Err(e) => CheckResult::unknown(format!("Failed to read results of plan {plan_id}: {e:#}")),
+    }
+}
+
+fn main() {
+    let arguments = Args::parse();
+    if let Some(Command::Check {
+        plan_id,
+        config_path,
+    }) = arguments.command
+    {
+        let result = check(&plan_id, config_path);
+        println!("{result}");
+        exit(result.state.exit_code());
+    }
+    let format = arguments.format.clone();
+    let (sections, pending_state) = collect_sections(arguments);
+    let mut stdout = io::stdout();
+    // If stdout is gone, there is no one left to report to. The sections do not count as delivered.
+    let printed = print_sections(&sections, &format, &mut stdout).and_then(|_| stdout.flush());
+    // If the state cannot be committed, the sections are simply delivered again. Dropping the
+    // pending state removes its temporary file.
+    if let (Some(pending_state), Ok(())) = (pending_state, printed) {
+        let _ = pending_state.commit();
+    }
 }
@@ -1,83 +1,378 @@
 use super::internal_config::{GlobalConfig, Plan};
-use robotmk::environment::Environment;
-use robotmk::lock::Locker;
-use robotmk::results::{BuildOutcome, BuildStates, EnvironmentBuildStage};
+use super::setup::provenance::environment_hash;
+use robotmk::environment::{Environment, RCCEnvironment};
+use robotmk::fs::{create_dir_all, remove_file};
+use robotmk::lock::{tolerate_timeout, Locker};
+use robotmk::results::{
+    BuildOutcome, BuildStates, EnvironmentBuildStage, NotNeededReason, ResourceUsage,
+};
 use robotmk::section::WriteSection;
 use robotmk::session::{RunSpec, Session};
 use robotmk::termination::{Cancelled, Outcome, Terminate};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use camino::{Utf8Path, Utf8PathBuf};
 use chrono::{DateTime, Utc};
-use log::{error, info};
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use tokio_util::sync::CancellationToken;
+use std::fs::{read, read_to_string, write};
+use std::panic::resume_unwind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
 
 pub fn environment_building_working_directory(working_directory: &Utf8Path) -> Utf8PathBuf {
     working_directory.join("environment_building")
 }
 
+// In contrast to the environment building working directory, this directory survives restarts of
+// the scheduler.
+pub fn environment_build_cache_directory(working_directory: &Utf8Path) -> Utf8PathBuf {
+    working_directory.join("environment_build_cache")
+}
+
+// Up to `environment_build_parallelism` environments are built at once, at most one per session.
+// The returned plans keep the order in which they were passed, such that the grouping established
+// during setup persists.
 pub fn build_environments(
     global_config: &GlobalConfig,
     plans: Vec<Plan>,
 ) -> Result<Vec<Plan>, Terminate> {
-    let mut build_stage_reporter = BuildStageReporter::new(
+    let build_stage_reporter = BuildStageReporter::new(
         plans.iter().map(|plan| plan.id.as_ref()),
         &global_config.results_directory,
         &global_config.results_directory_locker,
     )?;
     let working_directory =
         environment_building_working_directory(&global_config.working_directory);
+    let cache_directory = environment_build_cache_directory(&global_config.working_directory);
+    // All plans share the RCC binary, hence it is hashed only once.
+    let rcc_binary_digest = plans
+        .iter()
+        .any(|plan| matches!(plan.environment, Environment::Rcc(_)))
+        .then(|| rcc_binary_digest(&global_config.rcc_config.binary_path))
+        .flatten();
+    let built_plans = in_session_queues(
+        plans
+            .into_iter()
+            .map(|plan| (plan.session.id(), plan))
+            .collect(),
+        global_config.environment_build_parallelism,
+        |mut plan| {
+            let (outcome, resource_usage) = build_environment(
+                &plan,
+                Some(&build_stage_reporter),
+                rcc_binary_digest.as_deref(),
+                &working_directory,
+                &cache_directory,
+            )?;
+            plan.environment_build_resource_usage = resource_usage;
+            Ok((plan, outcome))
+        },
+    )?;
+    Ok(built_plans
+        .into_iter()
+        .filter_map(|(plan, outcome)| match outcome {
+            BuildOutcome::NotNeeded(_) | BuildOutcome::Success(_) => Some(plan),
+            _ => None,
+        })
+        .collect())
+}
 
-    let mut completed_plans = Vec::new();
-    for plan in plans.into_iter() {
-        let outcome = build_environment(
-            &plan.id,
-            &plan.environment,
-            &plan.session,
-            &global_config.cancellation_token,
-            &mut build_stage_reporter,
-            &working_directory,
-        )?;
-        match outcome {
-            BuildOutcome::NotNeeded | BuildOutcome::Success(_) => completed_plans.push(plan),
-            _ => {}
-        }
+// Builds within one session share the holotree of the session user, which RCC does not support
+// concurrently. Hence, every session gets its own queue, which is worked off by one worker at a
+// time. The results are returned in the order of the items. Once processing an item fails, the
+// workers stop taking further items.
+fn in_session_queues<T: Send, R: Send>(
+    items: Vec<(String, T)>,
+    parallelism: usize,
+    process: impl Fn(T) -> Result<R, Terminate> + Sync,
+) -> Result<Vec<R>, Terminate> {
+    let mut queues: Vec<Vec<(usize, T)>> = vec![];
+    let mut queue_indices = HashMap::new();
+    for (index, (session_id, item)) in items.into_iter().enumerate() {
+        let queue_index = *queue_indices.entry(session_id).or_insert_with(|| {
+            queues.push(vec![]);
+            queues.len() - 1
+        });
+        queues[queue_index].push((index, item));
+    }
+    let n_workers = parallelism.max(1).min(queues.len());
+    let pending_queues = Mutex::new(queues.into_iter());
+    let aborted = AtomicBool::new(false);
+
+    let worker_results: Vec<Result<Vec<_>, Terminate>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..n_workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let Some(queue) = pending_queues.lock().unwrap().next() else {
+                            return Ok(results);
+                        };
+                        for (index, item) in queue {
+                            if aborted.load(Ordering::Relaxed) {
+                                return Ok(results);
+                            }
+                            match process(item) {
+                                Ok(result) => results.push((index, result)),
+                                Err(error) => {
+                                    aborted.store(true, Ordering::Relaxed);
+                                    return Err(error);
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|panic| resume_unwind(panic)))
+            .collect()
+    });
+    let mut results = Vec::new();
+    for worker_result in worker_results {
+        results.extend(worker_result?);
     }
-    Ok(completed_plans)
+    results.sort_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// While scheduling, plan groups run concurrently. Their rebuilds are serialized per session, just
+// like the initial builds, which are all finished before scheduling starts.
+#[derive(Clone, Default)]
+pub struct SessionBuildLocks(Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>);
+
+impl SessionBuildLocks {
+    fn get(&self, session_id: String) -> Arc<Mutex<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(session_id)
+            .or_default()
+            .clone()
+    }
+}
+
+// Rebuilds the environment of a plan while scheduling, e.g. after its managed source changed. In
+// contrast to the initial build, the build stages are not reported. The rebuild happens in the
+// scheduling slot of the plan and hence delays the remaining plans of its group.
+pub fn rebuild_environment(
+    plan: &Plan,
+    working_directory: &Utf8Path,
+    session_build_locks: &SessionBuildLocks,
+) -> Result<(BuildOutcome, Option<ResourceUsage>), Terminate> {
+    let start_time = Instant::now();
+    let rcc_binary_digest = match &plan.environment {
+        Environment::Rcc(rcc_environment) => rcc_binary_digest(&rcc_environment.binary_path),
+        Environment::System(_) => None,
+    };
+    let session_build_lock = session_build_locks.get(plan.session.id());
+    let _session_build_guard = session_build_lock.lock().unwrap();
+    let outcome = build_environment(
+        plan,
+        None,
+        rcc_binary_digest.as_deref(),
+        &environment_building_working_directory(working_directory),
+        &environment_build_cache_directory(working_directory),
+    );
+    warn!(
+        "Plan {}: Rebuilding the environment delayed the plan group by {} s",
+        plan.id,
+        start_time.elapsed().as_secs()
+    );
+    outcome
 }
 
 fn build_environment(
-    id: &str,
-    environment: &Environment,
-    session: &Session,
-    cancellation_token: &CancellationToken,
-    build_stage_reporter: &mut BuildStageReporter,
+    plan: &Plan,
+    build_stage_reporter: Option<&BuildStageReporter>,
+    rcc_binary_digest: Option<&str>,
     working_directory: &Utf8Path,
-) -> Result<BuildOutcome, Terminate> {
-    let Some(build_instructions) = environment.build_instructions() else {
-        let outcome = BuildOutcome::NotNeeded;
+    cache_directory: &Utf8Path,
+) -> Result<(BuildOutcome, Option<ResourceUsage>), Terminate> {
+    let id = &plan.id;
+    let report = |stage: EnvironmentBuildStage| {
+        build_stage_reporter.map_or(Ok(()), |reporter| reporter.update(id, stage))
+    };
+    let Some(build_instructions) = plan.environment.build_instructions() else {
+        let outcome = BuildOutcome::NotNeeded(NotNeededReason::NoBuildRequired);
         info!("Nothing to do for plan {id}");
-        build_stage_reporter.update(id, EnvironmentBuildStage::Complete(outcome.clone()))?;
-        return Ok(outcome);
+        report(EnvironmentBuildStage::Complete(outcome.clone()))?;
+        return Ok((outcome, None));
     };
-    let base_path = &working_directory.join(session.id()).join(id);
+    let cache_entry = BuildCacheEntry::new(
+        cache_directory,
+        id,
+        &plan.environment,
+        &plan.session,
+        rcc_binary_digest,
+    );
+    let session_directory = working_directory.join(plan.session.id());
+    if cache_entry.is_hit()
+        && holotree_space_is_intact(
+            plan,
+            &session_directory.join(format!("{id}_verification")),
+            build_instructions.timeout,
+        )?
+    {
+        let outcome = BuildOutcome::NotNeeded(NotNeededReason::CacheHit);
+        info!("Environment for plan {id} was already built, skipping build");
+        report(EnvironmentBuildStage::Complete(outcome.clone()))?;
+        return Ok((outcome, None));
+    }
+    cache_entry.invalidate();
+    let base_path = &session_directory.join(id);
     info!("Building environment for plan {id}");
     let run_spec = RunSpec {
         id: &format!("robotmk_env_building_{id}"),
         command_spec: &build_instructions.command_spec,
         base_path,
         timeout: build_instructions.timeout,
-        cancellation_token,
+        resource_limits_config: None,
+        termination_config: None,
+        cancellation_token: &plan.cancellation_token,
     };
     let start_time = Utc::now();
-    build_stage_reporter.update(
-        id,
-        EnvironmentBuildStage::InProgress(start_time.timestamp()),
-    )?;
-    let outcome = run_build_command(id, &run_spec, session, start_time)?;
-    build_stage_reporter.update(id, EnvironmentBuildStage::Complete(outcome.clone()))?;
-    Ok(outcome)
+    report(EnvironmentBuildStage::InProgress(start_time.timestamp()))?;
+    let (outcome, resource_usage) = run_build_command(id, &run_spec, &plan.session, start_time)?;
+    cache_entry.record(&outcome);
+    report(EnvironmentBuildStage::Complete(outcome.clone()))?;
+    Ok((outcome, resource_usage))
+}
+
+// The recorded fingerprint does not notice if the holotree space was deleted outside of robotmk,
+// e.g. via `rcc holotree delete`. Hence, we let RCC check the space, `--no-build` makes it fail
+// instead of building.
+fn holotree_space_is_intact(
+    plan: &Plan,
+    base_path: &Utf8Path,
+    timeout: u64,
+) -> Result<bool, Cancelled> {
+    let Environment::Rcc(rcc_environment) = &plan.environment else {
+        return Ok(true);
+    };
+    let mut version_command_spec =
+        RCCEnvironment::bundled_command_spec(&rcc_environment.binary_path);
+    version_command_spec.add_argument("-v");
+    let run_spec = RunSpec {
+        id: &format!("robotmk_env_verification_{}", plan.id),
+        command_spec: &plan.environment.wrap(version_command_spec),
+        base_path,
+        timeout,
+        resource_limits_config: None,
+        termination_config: None,
+        cancellation_token: &plan.cancellation_token,
+    };
+    let run_report = match plan.session.run_and_report(&run_spec) {
+        Ok(run_report) => run_report,
+        Err(error) => {
+            warn!(
+                "Failed to verify environment of plan {}, rebuilding. Error: {error:?}",
+                plan.id
+            );
+            return Ok(false);
+        }
+    };
+    match run_report.outcome {
+        Outcome::Completed(0) => Ok(true),
+        Outcome::Completed(_) | Outcome::Timeout => {
+            warn!(
+                "Environment of plan {} was recorded as built, but is not usable, rebuilding. \
+                 See {base_path} for stdio logs",
+                plan.id
+            );
+            Ok(false)
+        }
+        Outcome::Cancel => Err(Cancelled {}),
+    }
+}
+
+// Records the fingerprint of the last successful build of a plan. The record is removed before
+// building, since a failed build leaves the holotree space in an unknown state.
+struct BuildCacheEntry {
+    path: Utf8PathBuf,
+    fingerprint: Option<String>,
+}
+
+impl BuildCacheEntry {
+    fn new(
+        cache_directory: &Utf8Path,
+        plan_id: &str,
+        environment: &Environment,
+        session: &Session,
+        rcc_binary_digest: Option<&str>,
+    ) -> Self {
+        Self {
+            path: cache_directory.join(plan_id),
+            fingerprint: build_fingerprint(environment, session, rcc_binary_digest),
+        }
+    }
+
+    fn is_hit(&self) -> bool {
+        self.fingerprint.as_ref().is_some_and(|fingerprint| {
+            read_to_string(&self.path).is_ok_and(|recorded| &recorded == fingerprint)
+        })
+    }
+
+    fn invalidate(&self) {
+        if self.path.exists() {
+            if let Err(error) = remove_file(&self.path) {
+                warn!("{error:?}");
+            }
+        }
+    }
+
+    fn record(&self, outcome: &BuildOutcome) {
+        let (BuildOutcome::Success(_), Some(fingerprint)) = (outcome, &self.fingerprint) else {
+            return;
+        };
+        if let Err(error) = self
+            .path
+            .parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| {
+                write(&self.path, fingerprint).context(format!("Failed to write {}", self.path))
+            })
+        {
+            warn!("Failed to record environment build. Error: {error:?}");
+        }
+    }
+}
+
+// The RCC binary is identified by its digest, which changes with every version.
+fn rcc_binary_digest(binary_path: &Utf8Path) -> Option<String> {
+    read(binary_path)
+        .map(|binary| format!("{:x}", Sha256::digest(binary)))
+        .map_err(|error| warn!("Failed to hash RCC binary {binary_path}. Error: {error:?}"))
+        .ok()
+}
+
+// Holotree spaces are per user, hence the session is part of the fingerprint.
+fn build_fingerprint(
+    environment: &Environment,
+    session: &Session,
+    rcc_binary_digest: Option<&str>,
+) -> Option<String> {
+    let Environment::Rcc(rcc_environment) = environment else {
+        return None;
+    };
+    let environment_hash = environment_hash(&rcc_environment.robot_yaml_path)?;
+    let mut hasher = Sha256::new();
+    for part in [
+        environment_hash.as_str(),
+        rcc_binary_digest?,
+        &rcc_environment.controller,
+        &rcc_environment.space,
+        &session.id(),
+    ] {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    Some(format!("{:x}", hasher.finalize()))
 }
 
 fn run_build_command(
@@ -85,24 +380,24 @@ fn run_build_command(
     run_spec: &RunSpec,
     session: &Session,
     reference_timestamp_for_duration: DateTime<Utc>,
-) -> Result<BuildOutcome, Cancelled> {
-    let outcome = match session.run(run_spec) {
-        Ok(o) => o,
+) -> Result<(BuildOutcome, Option<ResourceUsage>), Cancelled> {
+    let run_report = match session.run_and_report(run_spec) {
+        Ok(r) => r,
         Err(e) => {
             let log_error = e.context(anyhow!(
                 "Environment building failed, plan {id} will be dropped. See {} for stdio logs",
                 run_spec.base_path,
             ));
             error!("{log_error:?}");
-            return Ok(BuildOutcome::Error(format!("{log_error:?}")));
+            return Ok((BuildOutcome::Error(format!("{log_error:?}")), None));
         }
     };
     let duration = (Utc::now() - reference_timestamp_for_duration).num_seconds();
-    let exit_code = match outcome {
+    let exit_code = match run_report.outcome {
         Outcome::Completed(exit_code) => exit_code,
         Outcome::Timeout => {
             error!("Environment building timed out, plan {id} will be dropped");
-            return Ok(BuildOutcome::Timeout);
+            return Ok((BuildOutcome::Timeout, run_report.resource_usage));
         }
         Outcome::Cancel => {
             error!("Environment building cancelled, plan {id} will be dropped");
@@ -111,18 +406,23 @@ fn run_build_command(
     };
     if exit_code == 0 {
         info!("Environment building succeeded for plan {id}");
-        Ok(BuildOutcome::Success(duration))
+        Ok((BuildOutcome::Success(duration), run_report.resource_usage))
     } else {
         error!("Environment building not successful, plan {id} will be dropped");
-        Ok(BuildOutcome::Error(format!(
-            "Environment building not successful, see {} for stdio logs",
-            run_spec.base_path
-        )))
+        Ok((
+            BuildOutcome::Error(format!(
+                "Environment building not successful, see {} for stdio logs",
+                run_spec.base_path
+            )),
+            run_report.resource_usage,
+        ))
     }
 }
 
+// Builds report their stages concurrently. The states are written while holding the lock, such that
+// the file always reflects the latest update.
 struct BuildStageReporter<'a> {
-    build_states: HashMap<String, EnvironmentBuildStage>,
+    build_states: Mutex<HashMap<String, EnvironmentBuildStage>>,
     path: Utf8PathBuf,
     locker: &'a Locker,
 }
@@ -137,20 +437,174 @@ impl<'a> BuildStageReporter<'a> {
             .map(|id| (id.to_string(), EnvironmentBuildStage::Pending))
             .collect();
         let path = results_directory.join("environment_build_states.json");
-        BuildStates(&build_states).write(&path, locker)?;
+        tolerate_timeout(BuildStates(&build_states).write(&path, locker))?;
         Ok(Self {
-            build_states,
+            build_states: Mutex::new(build_states),
             path,
             locker,
         })
     }
 
     pub fn update(
-        &mut self,
+        &self,
         plan_id: &str,
         build_status: EnvironmentBuildStage,
     ) -> Result<(), Terminate> {
-        self.build_states.insert(plan_id.into(), build_status);
-        BuildStates(&self.build_states).write(&self.path, self.locker)
+        let mut build_states = self.build_states.lock().unwrap();
+        build_states.insert(plan_id.into(), build_status);
+        tolerate_timeout(BuildStates(&build_states).write(&self.path, self.locker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use robotmk::results::results_directory_lock_path;
+    use robotmk::section::Section;
+    use robotmk::session::CurrentSession;
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn session_queues_bound_concurrency() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let results_directory = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let lock_path = results_directory_lock_path(&results_directory);
+        write(&lock_path, "")?;
+        let locker = Locker::new(&lock_path, None);
+        let ids = ["a_1", "b_1", "a_2", "c_1", "b_2", "a_3", "d_1"];
+        let build_stage_reporter =
+            BuildStageReporter::new(ids.into_iter(), &results_directory, &locker)?;
+        let active_sessions = Mutex::new(vec![]);
+        let max_active = AtomicUsize::new(0);
+
+        let results = in_session_queues(
+            ids.iter().map(|id| (id[..1].to_string(), *id)).collect(),
+            2,
+            |id| {
+                let session = &id[..1];
+                {
+                    let mut active_sessions = active_sessions.lock().unwrap();
+                    assert!(!active_sessions.contains(&session));
+                    active_sessions.push(session);
+                    max_active.fetch_max(active_sessions.len(), Ordering::Relaxed);
+                }
+                build_stage_reporter.update(id, EnvironmentBuildStage::InProgress(0))?;
+                sleep(Duration::from_millis(20));
+                build_stage_reporter.update(
+                    id,
+                    EnvironmentBuildStage::Complete(BuildOutcome::Success(0)),
+                )?;
+                active_sessions
+                    .lock()
+                    .unwrap()
+                    .retain(|active_session| active_session != &session);
+                Ok(id)
+            },
+        )
+        .map_err(|_| anyhow!("Processing failed"))?;
+
+        assert_eq!(results, ids);
+        assert!(max_active.load(Ordering::Relaxed) <= 2);
+        let section: Section = serde_json::from_str(&read_to_string(
+            results_directory.join("environment_build_states.json"),
+        )?)?;
+        let build_states: HashMap<String, serde_json::Value> =
+            serde_json::from_str(&section.content)?;
+        assert_eq!(build_states.len(), ids.len());
+        assert!(build_states
+            .values()
+            .all(|stage| stage == &serde_json::json!({"Complete": {"Success": 0}})));
+        Ok(())
+    }
+
+    #[test]
+    fn session_queues_abort_on_error() {
+        let processed = AtomicUsize::new(0);
+        let result = in_session_queues(
+            ["a", "b", "a", "b", "a", "b"]
+                .iter()
+                .map(|session| (session.to_string(), *session))
+                .collect(),
+            2,
+            |session| {
+                processed.fetch_add(1, Ordering::Relaxed);
+                if session == "a" {
+                    sleep(Duration::from_millis(20));
+                    return Err(Terminate::Cancelled);
+                }
+                sleep(Duration::from_millis(100));
+                Ok(session)
+            },
+        );
+        assert!(matches!(result, Err(Terminate::Cancelled)));
+        assert_eq!(processed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn session_build_locks_are_shared_per_session() {
+        let session_build_locks = SessionBuildLocks::default();
+        let session_build_lock = session_build_locks.get("a".into());
+        let _session_build_guard = session_build_lock.lock().unwrap();
+        assert!(session_build_locks
+            .clone()
+            .get("a".into())
+            .try_lock()
+            .is_err());
+        assert!(session_build_locks.get("b".into()).try_lock().is_ok());
+    }
+
+    #[test]
+    fn build_cache_entry() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        write(
+            temp_dir_path.join("robot.yaml"),
+            "condaConfigFile: conda.yaml",
+        )?;
+        write(temp_dir_path.join("conda.yaml"), "python=3.10")?;
+        let environment = Environment::Rcc(RCCEnvironment {
+            binary_path: temp_dir_path.join("rcc"),
+            robot_yaml_path: temp_dir_path.join("robot.yaml"),
+            controller: "robotmk".into(),
+            space: "plan".into(),
+            build_timeout: 300,
+        });
+        let session = Session::Current(CurrentSession {});
+        let cache_directory = temp_dir_path.join("cache");
+        let rcc_binary_digest = Cell::new("digest");
+        let cache_entry = || {
+            BuildCacheEntry::new(
+                &cache_directory,
+                "plan",
+                &environment,
+                &session,
+                Some(rcc_binary_digest.get()),
+            )
+        };
+
+        assert!(!cache_entry().is_hit());
+        cache_entry().record(&BuildOutcome::Timeout);
+        assert!(!cache_entry().is_hit());
+        cache_entry().record(&BuildOutcome::Success(10));
+        assert!(cache_entry().is_hit());
+        assert!(
+            !BuildCacheEntry::new(&cache_directory, "plan", &environment, &session, None).is_hit()
+        );
+
+        rcc_binary_digest.set("new digest");
+        assert!(!cache_entry().is_hit());
+        cache_entry().record(&BuildOutcome::Success(10));
+        write(temp_dir_path.join("conda.yaml"), "python=3.12")?;
+        assert!(!cache_entry().is_hit());
+        cache_entry().record(&BuildOutcome::Success(10));
+        assert!(cache_entry().is_hit());
+
+        cache_entry().invalidate();
+        assert!(!cache_entry().is_hit());
+        Ok(())
     }
 }
@@ -0,0 +1,151 @@
+use crate::internal_config::GlobalConfig;
+
+use anyhow::{Context, Result as AnyhowResult};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use log::error;
+use robotmk::lock::Locker;
+use robotmk::results::{
+    scheduler_health_path, scheduler_heartbeat_path, LockContention, SchedulerHealth,
+    SchedulerHeartbeat, SCHEDULER_HEARTBEAT_INTERVAL,
+};
+use robotmk::section::WriteSection;
+use robotmk::termination::Terminate;
+use sha2::{Digest, Sha256};
+use std::fs::read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Bounds how long shutdown waits for the heartbeat thread.
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Shared between the heartbeat thread and the plan group loops, which report their progress.
+#[derive(Clone)]
+pub struct HealthState {
+    start_time: i64,
+    config_hash: String,
+    active_plan_group_loops: Arc<AtomicUsize>,
+    last_plan_completion: Arc<Mutex<Option<i64>>>,
+}
+
+impl HealthState {
+    pub fn new(config_path: &Utf8Path) -> AnyhowResult<Self> {
+        Ok(Self {
+            start_time: Utc::now().timestamp(),
+            config_hash: format!(
+                "{:x}",
+                Sha256::digest(read(config_path).context(format!("Failed to read {config_path}"))?)
+            ),
+            active_plan_group_loops: Arc::new(AtomicUsize::new(0)),
+            last_plan_completion: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn plan_group_loop_started(&self) {
+        self.active_plan_group_loops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn plan_group_loop_stopped(&self) {
+        self.active_plan_group_loops.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn plan_completed(&self) {
+        *self.last_plan_completion.lock().unwrap() = Some(Utc::now().timestamp());
+    }
+
+    fn health(&self, locker: &Locker, timestamp: i64) -> SchedulerHealth {
+        let wait_statistics = locker.wait_statistics();
+        SchedulerHealth {
+            timestamp,
+            pid: std::process::id(),
+            start_time: self.start_time,
+            uptime: timestamp - self.start_time,
+            version: env!("CARGO_PKG_VERSION").into(),
+            config_hash: self.config_hash.clone(),
+            active_plan_group_loops: self.active_plan_group_loops.load(Ordering::Relaxed),
+            last_plan_completion: *self.last_plan_completion.lock().unwrap(),
+            lock_contention: LockContention {
+                n_waits: wait_statistics.n_waits,
+                n_contended_waits: wait_statistics.n_contended_waits,
+                longest_wait: wait_statistics.longest_wait.as_secs_f64(),
+            },
+        }
+    }
+}
+
+// The heartbeat runs in its own thread, such that it is also renewed while the main thread is
+// blocked, e.g. by environment building.
+pub fn start_heartbeat(global_config: &GlobalConfig, health_state: &HealthState) -> JoinHandle<()> {
+    let results_directory = global_config.results_directory.clone();
+    let locker = global_config.results_directory_locker.clone();
+    let cancellation_token = global_config.cancellation_token.clone();
+    let health_state = health_state.clone();
+    spawn(move || run_heartbeat(results_directory, locker, cancellation_token, health_state))
+}
+
+// Plain thread without a runtime: Writing the sections blocks on the results directory lock, and the
+// locker must not be used from within an async context.
+fn run_heartbeat(
+    results_directory: Utf8PathBuf,
+    locker: Locker,
+    cancellation_token: CancellationToken,
+    health_state: HealthState,
+) {
+    loop {
+        beat(&results_directory, &locker, &health_state);
+        let next_beat = Instant::now() + Duration::from_secs(SCHEDULER_HEARTBEAT_INTERVAL);
+        while Instant::now() < next_beat {
+            if cancellation_token.is_cancelled() {
+                return;
+            }
+            sleep(CANCELLATION_POLL_INTERVAL);
+        }
+    }
+}
+
+fn beat(results_directory: &Utf8Path, locker: &Locker, health_state: &HealthState) {
+    let timestamp = Utc::now().timestamp();
+    if let Err(Terminate::Unrecoverable(error)) = (SchedulerHeartbeat { timestamp })
+        .write(scheduler_heartbeat_path(results_directory), locker)
+    {
+        error!("Failed to write scheduler heartbeat: {error:?}")
+    }
+    if let Err(Terminate::Unrecoverable(error)) = health_state
+        .health(locker, timestamp)
+        .write(scheduler_health_path(results_directory), locker)
+    {
+        error!("Failed to write scheduler health: {error:?}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use robotmk::results::results_directory_lock_path;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn beat_writes_heartbeat_and_health() -> AnyhowResult<()> {
+        let temp_dir = tempdir()?;
+        let results_directory = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let config_path = results_directory.join("robotmk.json");
+        write(&config_path, "{}")?;
+        let lock_path = results_directory_lock_path(&results_directory);
+        write(&lock_path, "")?;
+        let locker = Locker::new(&lock_path, None);
+
+        beat(
+            &results_directory,
+            &locker,
+            &HealthState::new(&config_path)?,
+        );
+
+        assert!(scheduler_heartbeat_path(&results_directory).is_file());
+        assert!(scheduler_health_path(&results_directory).is_file());
+        Ok(())
+    }
+}
@@ -1,15 +1,17 @@
 use robotmk::config::{
-    Config, PlanMetadata, RCCConfig, RobotConfig, Source as ConfigSource,
-    WorkingDirectoryCleanupConfig,
+    ArchiveVerificationConfig, Config, EnvironmentConfig, ManagedRobotsConfig, OrphanProcessPolicy,
+    PlanConfig, PlanMetadata, RCCConfig, ResourceLimitsConfig, RobotConfig, SectionConfig,
+    Source as ConfigSource, TerminationConfig, VersionConstraint, WorkingDirectoryCleanupConfig,
 };
 use robotmk::environment::Environment;
 use robotmk::lock::Locker;
-use robotmk::results::plan_results_directory;
+use robotmk::results::{plan_results_directory, Provenance, ResourceUsage, SourceKind};
 use robotmk::rf::robot::Robot;
 use robotmk::section::Host;
 use robotmk::session::Session;
 
 use camino::{Utf8Path, Utf8PathBuf};
+use std::time::SystemTime;
 use tokio_util::sync::CancellationToken;
 
 pub struct GlobalConfig {
@@ -17,17 +19,74 @@ pub struct GlobalConfig {
     pub results_directory: Utf8PathBuf,
     pub managed_directory: Utf8PathBuf,
     pub rcc_config: RCCConfig,
+    pub environment_build_parallelism: usize,
     pub cancellation_token: CancellationToken,
     pub results_directory_locker: Locker,
 }
 
 #[derive(Clone)]
 pub enum Source {
-    Manual,
-    Managed {
-        tar_gz_path: Utf8PathBuf,
-        target: Utf8PathBuf,
+    Manual { base_dir: Utf8PathBuf },
+    Managed(Box<ManagedSource>),
+    Git(Box<GitSource>),
+}
+
+impl Source {
+    // The directory below the managed directory which belongs to the plan
+    pub fn managed_target(&self) -> Option<&Utf8Path> {
+        match self {
+            Self::Manual { .. } => None,
+            Self::Managed(managed_source) => Some(&managed_source.target),
+            Self::Git(git_source) => Some(&git_source.target),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GitSource {
+    pub repository_url: String,
+    pub reference: String,
+    pub target: Utf8PathBuf,
+    // The commit which is currently checked out
+    pub commit: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ManagedSource {
+    pub origin: ArchiveOrigin,
+    pub target: Utf8PathBuf,
+    pub size_limit: Option<u64>,
+    pub managed_directory: Utf8PathBuf,
+    pub managed_directory_quota: Option<u64>,
+    // The configuration relative to the unpacked archive, required to switch to a new version.
+    pub robot_config: RobotConfig,
+    pub environment_config: EnvironmentConfig,
+    pub rcc_binary_path: Utf8PathBuf,
+    pub version: Option<ArchiveVersion>,
+    pub last_modified: Option<SystemTime>,
+}
+
+#[derive(Clone)]
+pub enum ArchiveOrigin {
+    File {
+        archive_path: Utf8PathBuf,
+        verification_config: Option<ArchiveVerificationConfig>,
     },
+    Repository {
+        repository_path: Utf8PathBuf,
+        robot: String,
+        version_constraint: VersionConstraint,
+        public_key: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveVersion {
+    // The version resolved from an artifact repository
+    pub label: Option<String>,
+    pub sha256: String,
+    pub source_dir: Utf8PathBuf,
+    pub unpacked_size: u64,
 }
 
 #[derive(Clone)]
@@ -37,15 +96,21 @@ pub struct Plan {
     pub working_directory: Utf8PathBuf,
     pub results_file: Utf8PathBuf,
     pub timeout: u64,
+    pub resource_limits_config: Option<ResourceLimitsConfig>,
+    pub termination_config: Option<TerminationConfig>,
+    pub orphan_process_policy: Option<OrphanProcessPolicy>,
     pub robot: Robot,
     pub environment: Environment,
     pub session: Session,
     pub working_directory_cleanup_config: WorkingDirectoryCleanupConfig,
     pub cancellation_token: CancellationToken,
     pub host: Host,
+    pub section_config: Option<SectionConfig>,
     pub results_directory_locker: Locker,
     pub metadata: PlanMetadata,
     pub group_affiliation: GroupAffiliation,
+    pub environment_build_resource_usage: Option<ResourceUsage>,
+    pub provenance: Provenance,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -65,15 +130,59 @@ pub fn from_external_config(
     for (group_index, sequential_group) in external_config.plan_groups.into_iter().enumerate() {
         for (plan_index, plan_config) in sequential_group.plans.into_iter().enumerate() {
             let (plan_source_dir, source) = match &plan_config.source {
-                ConfigSource::Manual { base_dir } => (base_dir.clone(), Source::Manual),
-                ConfigSource::Managed { tar_gz_path } => {
+                ConfigSource::Manual { base_dir } => (
+                    base_dir.clone(),
+                    Source::Manual {
+                        base_dir: base_dir.clone(),
+                    },
+                ),
+                ConfigSource::Managed {
+                    archive_path,
+                    verification_config,
+                    size_limit,
+                } => managed_source(
+                    ArchiveOrigin::File {
+                        archive_path: archive_path.clone(),
+                        verification_config: verification_config.clone(),
+                    },
+                    *size_limit,
+                    &plan_config,
+                    &external_config.managed_directory,
+                    external_config.managed_robots_config.as_ref(),
+                    &external_config.rcc_config.binary_path,
+                ),
+                ConfigSource::Repository {
+                    repository_path,
+                    robot,
+                    version_constraint,
+                    public_key,
+                    size_limit,
+                } => managed_source(
+                    ArchiveOrigin::Repository {
+                        repository_path: repository_path.clone(),
+                        robot: robot.clone(),
+                        version_constraint: version_constraint.clone(),
+                        public_key: public_key.clone(),
+                    },
+                    *size_limit,
+                    &plan_config,
+                    &external_config.managed_directory,
+                    external_config.managed_robots_config.as_ref(),
+                    &external_config.rcc_config.binary_path,
+                ),
+                ConfigSource::Git {
+                    repository_url,
+                    reference,
+                } => {
                     let target = external_config.managed_directory.join(&plan_config.id);
                     (
                         target.clone(),
-                        Source::Managed {
-                            tar_gz_path: tar_gz_path.clone(),
+                        Source::Git(Box::new(GitSource {
+                            repository_url: repository_url.clone(),
+                            reference: reference.clone(),
                             target,
-                        },
+                            commit: None,
+                        })),
                     )
                 }
             };
@@ -85,29 +194,11 @@ pub fn from_external_config(
                 results_file: plan_results_directory(&external_config.results_directory)
                     .join(format!("{}.json", plan_config.id)),
                 timeout: plan_config.execution_config.timeout,
+                resource_limits_config: plan_config.execution_config.resource_limits_config,
+                termination_config: plan_config.execution_config.termination_config,
+                orphan_process_policy: plan_config.execution_config.orphan_process_policy,
                 robot: Robot::new(
-                    RobotConfig {
-                        robot_target: plan_source_dir.join(plan_config.robot_config.robot_target),
-                        top_level_suite_name: plan_config.robot_config.top_level_suite_name,
-                        suites: plan_config.robot_config.suites,
-                        tests: plan_config.robot_config.tests,
-                        test_tags_include: plan_config.robot_config.test_tags_include,
-                        test_tags_exclude: plan_config.robot_config.test_tags_exclude,
-                        variables: plan_config.robot_config.variables,
-                        variable_files: plan_config
-                            .robot_config
-                            .variable_files
-                            .into_iter()
-                            .map(|f| plan_source_dir.join(f))
-                            .collect(),
-                        argument_files: plan_config
-                            .robot_config
-                            .argument_files
-                            .into_iter()
-                            .map(|f| plan_source_dir.join(f))
-                            .collect(),
-                        exit_on_failure: plan_config.robot_config.exit_on_failure,
-                    },
+                    rooted_robot_config(&plan_source_dir, &plan_config.robot_config),
                     plan_config.execution_config.n_attempts_max,
                     plan_config.execution_config.retry_strategy,
                 ),
@@ -121,6 +212,7 @@ pub fn from_external_config(
                 working_directory_cleanup_config: plan_config.working_directory_cleanup_config,
                 cancellation_token: cancellation_token.clone(),
                 host: plan_config.host,
+                section_config: plan_config.section_config,
                 results_directory_locker: results_directory_locker.clone(),
                 metadata: plan_config.metadata,
                 group_affiliation: GroupAffiliation {
@@ -128,6 +220,19 @@ pub fn from_external_config(
                     position_in_group: plan_index,
                     execution_interval: sequential_group.execution_interval,
                 },
+                environment_build_resource_usage: None,
+                provenance: Provenance {
+                    source_kind: match &plan_config.source {
+                        ConfigSource::Manual { .. } => SourceKind::Manual,
+                        ConfigSource::Managed { .. } => SourceKind::Managed,
+                        ConfigSource::Repository { .. } => SourceKind::Repository,
+                        ConfigSource::Git { .. } => SourceKind::Git,
+                    },
+                    source_version: None,
+                    source_hash: None,
+                    environment_hash: None,
+                    holotree_space: None,
+                },
             });
         }
     }
@@ -137,6 +242,9 @@ pub fn from_external_config(
             results_directory: external_config.results_directory,
             managed_directory: external_config.managed_directory,
             rcc_config: external_config.rcc_config,
+            environment_build_parallelism: external_config
+                .environment_build_parallelism
+                .unwrap_or(1),
             cancellation_token,
             results_directory_locker,
         },
@@ -144,6 +252,85 @@ pub fn from_external_config(
     )
 }
 
+fn managed_source(
+    origin: ArchiveOrigin,
+    size_limit: Option<u64>,
+    plan_config: &PlanConfig,
+    managed_directory: &Utf8Path,
+    managed_robots_config: Option<&ManagedRobotsConfig>,
+    rcc_binary_path: &Utf8Path,
+) -> (Utf8PathBuf, Source) {
+    let target = managed_directory.join(&plan_config.id);
+    (
+        target.clone(),
+        Source::Managed(Box::new(ManagedSource {
+            origin,
+            target,
+            size_limit: size_limit.or(managed_robots_config.and_then(|config| config.size_limit)),
+            managed_directory: managed_directory.into(),
+            managed_directory_quota: managed_robots_config.and_then(|config| config.quota),
+            robot_config: plan_config.robot_config.clone(),
+            environment_config: plan_config.environment_config.clone(),
+            rcc_binary_path: rcc_binary_path.into(),
+            version: None,
+            last_modified: None,
+        })),
+    )
+}
+
+impl ManagedSource {
+    // The file whose modification indicates that a new version might be available
+    pub fn watched_path(&self) -> Utf8PathBuf {
+        match &self.origin {
+            ArchiveOrigin::File { archive_path, .. } => archive_path.clone(),
+            ArchiveOrigin::Repository {
+                repository_path, ..
+            } => repository_index_path(repository_path),
+        }
+    }
+}
+
+pub fn repository_index_path(repository_path: &Utf8Path) -> Utf8PathBuf {
+    repository_path.join("index.json")
+}
+
+impl Plan {
+    // Points the plan to a new version of its managed source. Has no effect for manual sources.
+    pub fn switch_source_dir(&mut self, source_dir: &Utf8Path) {
+        let Source::Managed(managed_source) = &self.source else {
+            return;
+        };
+        self.robot = Robot::new(
+            rooted_robot_config(source_dir, &managed_source.robot_config),
+            self.robot.n_attempts_max,
+            self.robot.retry_strategy.clone(),
+        );
+        self.environment = Environment::new(
+            source_dir,
+            &self.id,
+            &managed_source.rcc_binary_path,
+            &managed_source.environment_config,
+        );
+    }
+}
+
+fn rooted_robot_config(source_dir: &Utf8Path, robot_config: &RobotConfig) -> RobotConfig {
+    RobotConfig {
+        robot_target: source_dir.join(&robot_config.robot_target),
+        variable_files: robot_config
+            .variable_files
+            .iter()
+            .map(|f| source_dir.join(f))
+            .collect(),
+        argument_files: robot_config
+            .argument_files
+            .iter()
+            .map(|f| source_dir.join(f))
+            .collect(),
+        ..robot_config.clone()
+    }
+}
+
 pub fn sort_plans_by_grouping(plans: &mut [Plan]) {
     plans.sort_by_key(|plan| {
         (
@@ -189,11 +376,15 @@ mod tests {
                 n_attempts_max: 1,
                 retry_strategy: RetryStrategy::Incremental,
                 timeout: 60,
+                resource_limits_config: None,
+                termination_config: None,
+                orphan_process_policy: None,
             },
             environment_config: EnvironmentConfig::System,
             session_config: SessionConfig::Current,
             working_directory_cleanup_config: WorkingDirectoryCleanupConfig::MaxAgeSecs(1209600),
             host: Host::Source,
+            section_config: None,
             metadata: PlanMetadata {
                 application: "sys_app".into(),
                 suite_name: "my_first_suite".into(),
@@ -224,6 +415,9 @@ mod tests {
                 n_attempts_max: 1,
                 retry_strategy: RetryStrategy::Complete,
                 timeout: 60,
+                resource_limits_config: None,
+                termination_config: None,
+                orphan_process_policy: None,
             },
             environment_config: EnvironmentConfig::Rcc(RCCEnvironmentConfig {
                 robot_yaml_path: Utf8PathBuf::from("robot.yaml"),
@@ -237,6 +431,7 @@ mod tests {
             }),
             working_directory_cleanup_config: WorkingDirectoryCleanupConfig::MaxExecutions(50),
             host: Host::Source,
+            section_config: None,
             metadata: PlanMetadata {
                 application: "rcc_app".into(),
                 suite_name: "my_second_suite".into(),
@@ -253,6 +448,9 @@ mod tests {
                 working_directory: Utf8PathBuf::from("/working"),
                 results_directory: Utf8PathBuf::from("/results"),
                 managed_directory: Utf8PathBuf::from("/managed_robots"),
+                managed_robots_config: None,
+                environment_build_parallelism: None,
+                results_directory_lock_timeout: None,
                 rcc_config: RCCConfig {
                     binary_path: Utf8PathBuf::from("/bin/rcc"),
                     profile_config: RCCProfileConfig::Custom(CustomRCCProfileConfig {
@@ -378,4 +576,66 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_switch_source_dir() {
+        let cancellation_token = CancellationToken::new();
+        let (_, mut plans) = from_external_config(
+            Config {
+                working_directory: Utf8PathBuf::from("/working"),
+                results_directory: Utf8PathBuf::from("/results"),
+                managed_directory: Utf8PathBuf::from("/managed_robots"),
+                managed_robots_config: None,
+                environment_build_parallelism: None,
+                results_directory_lock_timeout: None,
+                rcc_config: RCCConfig {
+                    binary_path: Utf8PathBuf::from("/bin/rcc"),
+                    profile_config: RCCProfileConfig::Default,
+                },
+                plan_groups: vec![SequentialPlanGroup {
+                    plans: vec![PlanConfig {
+                        source: ConfigSource::Managed {
+                            archive_path: "/archives/rcc.tar.gz".into(),
+                            verification_config: None,
+                            size_limit: None,
+                        },
+                        ..rcc_plan_config()
+                    }],
+                    execution_interval: 300,
+                }],
+            },
+            cancellation_token.clone(),
+            Locker::new("/config.json", Some(&cancellation_token)),
+        );
+        let plan = &mut plans[0];
+        assert_eq!(
+            plan.robot.robot_target,
+            Utf8PathBuf::from("/managed_robots/rcc/tasks.robot")
+        );
+        plan.switch_source_dir(Utf8Path::new("/managed_robots/rcc/0123456789abcdef"));
+        assert_eq!(
+            plan.robot,
+            Robot {
+                robot_target: Utf8PathBuf::from("/managed_robots/rcc/0123456789abcdef/tasks.robot"),
+                command_line_args: vec![
+                    "--variablefile".into(),
+                    "/managed_robots/rcc/0123456789abcdef/vars.txt".into()
+                ],
+                n_attempts_max: 1,
+                retry_strategy: RetryStrategy::Complete,
+            }
+        );
+        assert_eq!(
+            plan.environment,
+            Environment::Rcc(RCCEnvironment {
+                binary_path: Utf8PathBuf::from("/bin/rcc"),
+                robot_yaml_path: Utf8PathBuf::from(
+                    "/managed_robots/rcc/0123456789abcdef/robot.yaml"
+                ),
+                controller: "robotmk".into(),
+                space: "rcc".into(),
+                build_timeout: 300,
+            })
+        );
+    }
 }
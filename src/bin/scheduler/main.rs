@@ -1,5 +1,6 @@
 mod build;
 mod cli;
+mod heartbeat;
 mod internal_config;
 mod logging;
 mod scheduling;
@@ -10,14 +11,18 @@ use anyhow::{Context, Result as AnyhowResult};
 use clap::Parser;
 use log::info;
 use logging::log_and_return_error;
-use robotmk::lock::Locker;
-use robotmk::results::{SchedulerPhase, SetupFailure, SetupFailures};
+use robotmk::lock::{tolerate_timeout, Locker};
+use robotmk::results::{results_directory_lock_path, SchedulerPhase, SetupFailure, SetupFailures};
 use robotmk::section::WriteSection;
 use robotmk::termination::Terminate;
 use std::time::Duration;
 use tokio::time::{timeout_at, Instant};
 use tokio_util::sync::CancellationToken;
 
+// A stuck lock holder must not block the scheduler forever. Waiting longer than the agent plugin
+// does, since a write which is given up on loses data.
+const DEFAULT_RESULTS_DIRECTORY_LOCK_TIMEOUT: u64 = 60;
+
 fn main() -> AnyhowResult<()> {
     if let Err(e) = run() {
         return match e {
@@ -39,15 +44,26 @@ fn run() -> Result<(), Terminate> {
     let external_config =
         robotmk::config::load(&args.config_path).context("Configuration loading failed")?;
     info!("Configuration loaded");
+    let health_state =
+        heartbeat::HealthState::new(&args.config_path).context("Failed to set up health state")?;
 
     let cancellation_token = termination::start_termination_control(args.run_flag)
         .context("Failed to set up termination control")?;
     info!("Termination control set up");
 
+    let results_directory_locker = Locker::new(
+        results_directory_lock_path(&external_config.results_directory),
+        Some(&cancellation_token),
+    )
+    .with_timeout(Duration::from_secs(
+        external_config
+            .results_directory_lock_timeout
+            .unwrap_or(DEFAULT_RESULTS_DIRECTORY_LOCK_TIMEOUT),
+    ));
     let (global_config, plans) = internal_config::from_external_config(
         external_config,
         cancellation_token.clone(),
-        Locker::new(&args.config_path, Some(&cancellation_token)),
+        results_directory_locker,
     );
 
     if global_config.cancellation_token.is_cancelled() {
@@ -57,11 +73,21 @@ fn run() -> Result<(), Terminate> {
 
     let (plans, general_setup_failures) = setup::general::setup(&global_config, plans)?;
     info!("General setup completed");
+    heartbeat::start_heartbeat(&global_config, &health_state);
 
     write_phase(&SchedulerPhase::ManagedRobots, &global_config)?;
     let (plans, unpacking_managed_failures) = setup::unpack_managed::setup(plans);
+    tolerate_timeout(setup::unpack_managed::managed_robots(&plans).write(
+        global_config.results_directory.join("managed_robots.json"),
+        &global_config.results_directory_locker,
+    ))?;
+    let (plans, git_failures) = setup::git::setup(&global_config, plans)?;
+    let plans = setup::provenance::setup(plans);
     info!("Managed robot setup completed");
 
+    let (plans, resource_limits_failures) = setup::resource_limits::setup(plans);
+    info!("Resource limits setup completed");
+
     if let Some(grace_period) = args.grace_period {
         info!("Grace period: Sleeping for {grace_period} seconds");
         write_phase(&SchedulerPhase::GracePeriod(grace_period), &global_config)?;
@@ -80,6 +106,8 @@ fn run() -> Result<(), Terminate> {
         general_setup_failures
             .into_iter()
             .chain(unpacking_managed_failures)
+            .chain(git_failures)
+            .chain(resource_limits_failures)
             .chain(rcc_setup_failures),
         &global_config,
     )?;
@@ -97,7 +125,7 @@ fn run() -> Result<(), Terminate> {
 
     info!("Starting plan scheduling");
     write_phase(&SchedulerPhase::Scheduling, &global_config)?;
-    scheduling::scheduler::run_plans_and_cleanup(&global_config, &plans);
+    scheduling::scheduler::run_plans_and_cleanup(&global_config, &plans, &health_state);
     info!("Terminated");
     Ok(())
 }
@@ -106,20 +134,20 @@ fn write_phase(
     phase: &SchedulerPhase,
     global_config: &internal_config::GlobalConfig,
 ) -> Result<(), Terminate> {
-    phase.write(
+    tolerate_timeout(phase.write(
         global_config.results_directory.join("scheduler_phase.json"),
         &global_config.results_directory_locker,
-    )
+    ))
 }
 
 fn write_setup_failures(
     failures: impl Iterator<Item = SetupFailure>,
     global_config: &internal_config::GlobalConfig,
 ) -> Result<(), Terminate> {
-    SetupFailures(failures.collect()).write(
+    tolerate_timeout(SetupFailures(failures.collect()).write(
         global_config.results_directory.join("setup_failures.json"),
         &global_config.results_directory_locker,
-    )
+    ))
 }
 
 #[tokio::main]
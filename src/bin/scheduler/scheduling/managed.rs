@@ -0,0 +1,194 @@
+use crate::build::{rebuild_environment, SessionBuildLocks};
+use crate::internal_config::{ArchiveOrigin, ArchiveVersion, Plan, Source};
+use crate::setup::provenance::{environment_hash, provenance};
+use crate::setup::unpack_managed::{modification_time, unpack_version};
+use robotmk::environment::Environment;
+use robotmk::fs::remove_dir_all;
+use robotmk::results::BuildOutcome;
+use robotmk::termination::Terminate;
+
+use camino::Utf8Path;
+use log::{error, info, warn};
+
+// Checks whether the archive of a managed plan changed since we last looked at it. If so, the new
+// version is unpacked next to the current one and the plan is switched over. If unpacking or
+// rebuilding the environment fails, the plan keeps running with the current version. Failed
+// unpacking is retried on the next check, e.g. in case the archive was still being written.
+// Archives pinned to a digest are never updated, a new version requires a new configuration.
+pub fn update_managed_source(
+    plan: &mut Plan,
+    working_directory: &Utf8Path,
+    session_build_locks: &SessionBuildLocks,
+) -> Result<(), Terminate> {
+    let Source::Managed(managed_source) = &mut plan.source else {
+        return Ok(());
+    };
+    let Some(current_version) = managed_source.version.clone() else {
+        return Ok(());
+    };
+    let modified = match modification_time(&managed_source.watched_path()) {
+        Ok(modified) => modified,
+        Err(error) => {
+            warn!("Plan {}: {error:?}", plan.id);
+            return Ok(());
+        }
+    };
+    if managed_source.last_modified == Some(modified) {
+        return Ok(());
+    }
+    if let ArchiveOrigin::File {
+        verification_config: Some(verification_config),
+        ..
+    } = &managed_source.origin
+    {
+        warn!(
+            "Plan {}: Archive changed, but is pinned to digest {}, keeping current version",
+            plan.id, verification_config.sha256
+        );
+        managed_source.last_modified = Some(modified);
+        return Ok(());
+    }
+    let new_version = match unpack_version(managed_source) {
+        Ok(Some(new_version)) => {
+            managed_source.last_modified = Some(modified);
+            new_version
+        }
+        Ok(None) => {
+            managed_source.last_modified = Some(modified);
+            return Ok(());
+        }
+        Err(failure) => {
+            error!(
+                "Plan {}: {}, keeping current version. Error: {:?}",
+                plan.id, failure.summary, failure.error
+            );
+            return Ok(());
+        }
+    };
+    info!(
+        "Plan {}: Unpacked new version {} into `{}` ({} B)",
+        plan.id,
+        new_version.label.as_deref().unwrap_or(&new_version.sha256),
+        new_version.source_dir,
+        new_version.unpacked_size
+    );
+
+    let mut updated_plan = plan.clone();
+    updated_plan.switch_source_dir(&new_version.source_dir);
+    if environment_changed(&plan.environment, &updated_plan.environment) {
+        match rebuild_environment(&updated_plan, working_directory, session_build_locks)? {
+            (BuildOutcome::NotNeeded(_) | BuildOutcome::Success(_), resource_usage) => {
+                updated_plan.environment_build_resource_usage = resource_usage;
+            }
+            _ => {
+                error!(
+                    "Plan {}: Environment building failed for new version, keeping current version",
+                    plan.id
+                );
+                discard_version(&new_version);
+                restore_environment(plan, working_directory, session_build_locks)?;
+                return Ok(());
+            }
+        }
+    }
+    if let Source::Managed(managed_source) = &mut updated_plan.source {
+        managed_source.version = Some(new_version);
+    }
+    updated_plan.provenance = provenance(&updated_plan);
+    *plan = updated_plan;
+    discard_version(&current_version);
+    info!(
+        "Plan {}: Switched to new version of managed source",
+        plan.id
+    );
+    Ok(())
+}
+
+fn discard_version(version: &ArchiveVersion) {
+    if let Err(error) = remove_dir_all(&version.source_dir) {
+        warn!("{error:?}");
+    }
+}
+
+// The new version was built into the same holotree space, so the failed build left the environment
+// of the current version in an unknown state.
+fn restore_environment(
+    plan: &Plan,
+    working_directory: &Utf8Path,
+    session_build_locks: &SessionBuildLocks,
+) -> Result<(), Terminate> {
+    match rebuild_environment(plan, working_directory, session_build_locks)? {
+        (BuildOutcome::NotNeeded(_) | BuildOutcome::Success(_), _) => {
+            info!("Plan {}: Restored environment of current version", plan.id)
+        }
+        _ => error!(
+            "Plan {}: Failed to restore environment of current version",
+            plan.id
+        ),
+    }
+    Ok(())
+}
+
+fn environment_changed(current: &Environment, new: &Environment) -> bool {
+    let (Environment::Rcc(current), Environment::Rcc(new)) = (current, new) else {
+        return false;
+    };
+    match (
+        environment_hash(&current.robot_yaml_path),
+        environment_hash(&new.robot_yaml_path),
+    ) {
+        (Some(current_hash), Some(new_hash)) => current_hash != new_hash,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf;
+    use robotmk::environment::{RCCEnvironment, SystemEnvironment};
+    use std::fs::{create_dir, write};
+    use tempfile::tempdir;
+
+    fn rcc_environment(robot_yaml_path: Utf8PathBuf) -> Environment {
+        Environment::Rcc(RCCEnvironment {
+            binary_path: "/bin/rcc".into(),
+            robot_yaml_path,
+            controller: "robotmk".into(),
+            space: "plan".into(),
+            build_timeout: 300,
+        })
+    }
+
+    #[test]
+    fn test_environment_changed() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        for (version, conda_yaml) in [
+            ("v1", "python=3.10"),
+            ("v2", "python=3.10"),
+            ("v3", "python=3.12"),
+        ] {
+            create_dir(temp_dir_path.join(version))?;
+            write(
+                temp_dir_path.join(version).join("robot.yaml"),
+                "condaConfigFile: conda.yaml",
+            )?;
+            write(temp_dir_path.join(version).join("conda.yaml"), conda_yaml)?;
+        }
+        let environment =
+            |version: &str| rcc_environment(temp_dir_path.join(version).join("robot.yaml"));
+
+        assert!(!environment_changed(&environment("v1"), &environment("v2")));
+        assert!(environment_changed(&environment("v1"), &environment("v3")));
+        assert!(environment_changed(
+            &environment("v1"),
+            &environment("missing")
+        ));
+        assert!(!environment_changed(
+            &Environment::System(SystemEnvironment {}),
+            &Environment::System(SystemEnvironment {})
+        ));
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 mod cleanup;
+mod managed;
 mod plans;
 pub mod scheduler;
@@ -1,28 +1,37 @@
 use crate::internal_config::Plan;
 use crate::logging::TIMESTAMP_FORMAT;
-use robotmk::plans::run_attempts_with_rebot;
+use robotmk::plans::{run_attempts_with_rebot, AttemptsSpec};
 use robotmk::results::{AttemptsConfig, PlanExecutionReport};
 
 use anyhow::{Context, Result as AnyhowResult};
 use chrono::Utc;
 use log::info;
-use robotmk::section::WritePiggybackSection;
+use robotmk::section::{Section, WritePiggybackSection};
 use std::fs::create_dir_all;
 
 pub fn run_plan(plan: &Plan) -> AnyhowResult<()> {
     info!("Running plan {}", &plan.id);
-    produce_plan_results(plan)?
-        .write(
-            &plan.results_file,
-            plan.host.clone(),
-            &plan.results_directory_locker,
-        )
+    plan_results_section(plan, produce_plan_results(plan)?)
+        .write(&plan.results_file, &plan.results_directory_locker)
         .context("Reporting plan results failed")?;
     info!("Plan {} finished", &plan.id);
 
     Ok(())
 }
 
+fn plan_results_section(plan: &Plan, mut report: PlanExecutionReport) -> Section {
+    let Some(section_config) = &plan.section_config else {
+        return report.section(plan.host.clone(), None);
+    };
+    let compression = section_config.compression.as_ref();
+    match section_config.size_budget {
+        Some(size_budget) => {
+            report.section_within_budget(plan.host.clone(), compression, size_budget)
+        }
+        None => report.section(plan.host.clone(), compression),
+    }
+}
+
 fn produce_plan_results(plan: &Plan) -> AnyhowResult<PlanExecutionReport> {
     let timestamp = Utc::now();
     let output_directory = plan
@@ -34,15 +43,18 @@ fn produce_plan_results(plan: &Plan) -> AnyhowResult<PlanExecutionReport> {
         output_directory
     ))?;
 
-    let (attempt_reports, rebot) = run_attempts_with_rebot(
-        &plan.robot,
-        &plan.id,
-        &plan.environment,
-        &plan.session,
-        plan.timeout,
-        &plan.cancellation_token,
-        &output_directory,
-    )
+    let (attempt_reports, rebot) = run_attempts_with_rebot(&AttemptsSpec {
+        id: &plan.id,
+        robot: &plan.robot,
+        environment: &plan.environment,
+        session: &plan.session,
+        timeout: plan.timeout,
+        resource_limits_config: plan.resource_limits_config.as_ref(),
+        termination_config: plan.termination_config.as_ref(),
+        orphan_process_policy: plan.orphan_process_policy.as_ref(),
+        cancellation_token: &plan.cancellation_token,
+        output_directory: &output_directory,
+    })
     .context("Received termination signal while running plan")?;
 
     Ok(PlanExecutionReport {
@@ -56,5 +68,8 @@ fn produce_plan_results(plan: &Plan) -> AnyhowResult<PlanExecutionReport> {
             n_attempts_max: plan.robot.n_attempts_max,
         },
         metadata: plan.metadata.clone(),
+        provenance: plan.provenance.clone(),
+        environment_build_resource_usage: plan.environment_build_resource_usage.clone(),
+        size_budget_exceeded: false,
     })
 }
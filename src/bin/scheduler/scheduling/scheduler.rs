@@ -1,8 +1,13 @@
 use super::cleanup::cleanup_working_directories;
+use super::managed::update_managed_source;
 use super::plans::run_plan;
+use crate::build::SessionBuildLocks;
+use crate::heartbeat::HealthState;
 use crate::internal_config::{GlobalConfig, Plan};
 use crate::logging::log_and_return_error;
 
+use anyhow::anyhow;
+use camino::Utf8PathBuf;
 use chrono::Utc;
 use log::{error, info};
 use std::collections::HashMap;
@@ -12,7 +17,11 @@ use tokio::time::{interval_at, Instant};
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
-pub async fn run_plans_and_cleanup(global_config: &GlobalConfig, plans: &[Plan]) {
+pub async fn run_plans_and_cleanup(
+    global_config: &GlobalConfig,
+    plans: &[Plan],
+    health_state: &HealthState,
+) {
     let mut plans_by_exec_group = HashMap::new();
     for plan in plans {
         plans_by_exec_group
@@ -24,13 +33,17 @@ pub async fn run_plans_and_cleanup(global_config: &GlobalConfig, plans: &[Plan])
             .push(plan.clone());
     }
 
+    let session_build_locks = SessionBuildLocks::default();
     let mut join_set = JoinSet::new();
     for ((_, execution_interval), mut plans) in plans_by_exec_group {
         plans.sort_by_key(|plan| plan.group_affiliation.position_in_group);
         join_set.spawn(run_sequential_plan_group_scheduler(
             execution_interval,
             plans,
+            global_config.working_directory.clone(),
+            session_build_locks.clone(),
             global_config.cancellation_token.clone(),
+            health_state.clone(),
         ));
     }
 
@@ -50,9 +63,13 @@ pub async fn run_plans_and_cleanup(global_config: &GlobalConfig, plans: &[Plan])
 
 async fn run_sequential_plan_group_scheduler(
     interval: u64,
-    plans: Vec<Plan>,
+    mut plans: Vec<Plan>,
+    working_directory: Utf8PathBuf,
+    session_build_locks: SessionBuildLocks,
     cancellation_token: CancellationToken,
+    health_state: HealthState,
 ) {
+    health_state.plan_group_loop_started();
     // It is debatable whether MissedTickBehavior::Burst (the default) is correct. In practice, as
     // long as timeout * number of attempts is shorter than the execution interval, it shouldn't
     // make a difference anyway.  However, in case we consider changing this, note that using
@@ -64,10 +81,33 @@ async fn run_sequential_plan_group_scheduler(
     loop {
         tokio::select! {
             _ = clock.tick() => { }
-            _ = cancellation_token.cancelled() => { return }
+            _ = cancellation_token.cancelled() => {
+                health_state.plan_group_loop_stopped();
+                return
+            }
         };
-        for plan in plans.clone() {
-            let _ = spawn_blocking(move || run_plan(&plan).map_err(log_and_return_error)).await;
+        for plan in plans.iter_mut() {
+            let mut current_plan = plan.clone();
+            let working_directory = working_directory.clone();
+            let session_build_locks = session_build_locks.clone();
+            if let Ok((updated_plan, outcome)) = spawn_blocking(move || {
+                let outcome = update_managed_source(
+                    &mut current_plan,
+                    &working_directory,
+                    &session_build_locks,
+                )
+                .map_err(|error| anyhow!(error))
+                .and_then(|_| run_plan(&current_plan))
+                .map_err(log_and_return_error);
+                (current_plan, outcome)
+            })
+            .await
+            {
+                *plan = updated_plan;
+                if outcome.is_ok() {
+                    health_state.plan_completed();
+                }
+            }
         }
     }
 }
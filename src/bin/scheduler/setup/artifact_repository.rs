@@ -0,0 +1,217 @@
+use crate::internal_config::{repository_index_path, ArchiveOrigin};
+use anyhow::{bail, Context};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use robotmk::config::{ArchiveVerificationConfig, SignatureConfig, VersionConstraint};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+
+#[derive(Deserialize)]
+struct Index {
+    robots: HashMap<String, Vec<IndexEntry>>,
+}
+
+// The archive is located relative to `<repository>/<robot>`.
+#[derive(Deserialize)]
+struct IndexEntry {
+    version: String,
+    archive: Utf8PathBuf,
+    sha256: String,
+}
+
+pub struct ResolvedArchive {
+    pub archive_path: Utf8PathBuf,
+    pub verification_config: Option<ArchiveVerificationConfig>,
+    pub label: Option<String>,
+}
+
+pub fn resolve(origin: &ArchiveOrigin) -> anyhow::Result<ResolvedArchive> {
+    match origin {
+        ArchiveOrigin::File {
+            archive_path,
+            verification_config,
+        } => Ok(ResolvedArchive {
+            archive_path: archive_path.clone(),
+            verification_config: verification_config.clone(),
+            label: None,
+        }),
+        ArchiveOrigin::Repository {
+            repository_path,
+            robot,
+            version_constraint,
+            public_key,
+        } => resolve_from_repository(repository_path, robot, version_constraint, public_key),
+    }
+}
+
+// Archives from a repository are always verified against the digest listed in the index.
+fn resolve_from_repository(
+    repository_path: &Utf8Path,
+    robot: &str,
+    version_constraint: &VersionConstraint,
+    public_key: &Option<String>,
+) -> anyhow::Result<ResolvedArchive> {
+    let index_path = repository_index_path(repository_path);
+    let index: Index = serde_json::from_str(
+        &read_to_string(&index_path).context(format!("Failed to read {index_path}"))?,
+    )
+    .context(format!("Failed to parse {index_path}"))?;
+    let Some(entries) = index.robots.get(robot) else {
+        bail!("Robot {robot} is not listed in {index_path}")
+    };
+    let (version, entry) = select_version(entries, version_constraint)?;
+    if !entry
+        .archive
+        .components()
+        .all(|component| matches!(component, Utf8Component::Normal(_)))
+    {
+        bail!(
+            "Archive `{}` of {robot} {version} must be a relative path within the repository",
+            entry.archive
+        )
+    }
+    let archive_path = repository_path.join(robot).join(&entry.archive);
+    Ok(ResolvedArchive {
+        verification_config: Some(ArchiveVerificationConfig {
+            sha256: entry.sha256.clone(),
+            signature_config: public_key.as_ref().map(|public_key| SignatureConfig {
+                signature_path: Utf8PathBuf::from(format!("{archive_path}.sig")),
+                public_key: public_key.clone(),
+            }),
+        }),
+        archive_path,
+        label: Some(version.to_string()),
+    })
+}
+
+// `Latest` never selects pre-releases. Entries whose version is not valid semver are ignored.
+fn select_version<'a>(
+    entries: &'a [IndexEntry],
+    version_constraint: &VersionConstraint,
+) -> anyhow::Result<(Version, &'a IndexEntry)> {
+    let requirement = match version_constraint {
+        VersionConstraint::Latest => None,
+        VersionConstraint::Requirement(requirement) => Some(
+            VersionReq::parse(requirement)
+                .context(format!("Invalid version requirement `{requirement}`"))?,
+        ),
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            Version::parse(&entry.version)
+                .ok()
+                .map(|version| (version, entry))
+        })
+        .filter(|(version, _)| match &requirement {
+            Some(requirement) => requirement.matches(version),
+            None => version.pre.is_empty(),
+        })
+        .max_by(|(left, _), (right, _)| left.cmp(right))
+        .context(match version_constraint {
+            VersionConstraint::Latest => "No released version available".to_string(),
+            VersionConstraint::Requirement(requirement) => {
+                format!("No version matches `{requirement}`")
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<IndexEntry> {
+        ["1.0.0", "1.2.0", "1.10.1", "2.0.0-rc.1", "not-a-version"]
+            .into_iter()
+            .map(|version| IndexEntry {
+                version: version.into(),
+                archive: format!("{version}.tar.gz").into(),
+                sha256: "".into(),
+            })
+            .collect()
+    }
+
+    fn selected(version_constraint: VersionConstraint) -> anyhow::Result<String> {
+        Ok(select_version(&entries(), &version_constraint)?
+            .0
+            .to_string())
+    }
+
+    #[test]
+    fn select_latest() -> anyhow::Result<()> {
+        assert_eq!(selected(VersionConstraint::Latest)?, "1.10.1");
+        Ok(())
+    }
+
+    #[test]
+    fn select_by_requirement() -> anyhow::Result<()> {
+        assert_eq!(
+            selected(VersionConstraint::Requirement("~1.2".into()))?,
+            "1.2.0"
+        );
+        assert_eq!(
+            selected(VersionConstraint::Requirement(">=2.0.0-rc.1".into()))?,
+            "2.0.0-rc.1"
+        );
+        let error = selected(VersionConstraint::Requirement("^3".into())).unwrap_err();
+        assert_eq!(error.to_string(), "No version matches `^3`");
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_from_index() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let repository_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        std::fs::write(
+            repository_path.join("index.json"),
+            r#"{"robots": {"robot": [
+                {"version": "1.0.0", "archive": "1.0.0.tar.gz", "sha256": "aa"},
+                {"version": "1.1.0", "archive": "1.1.0.zip", "sha256": "bb"},
+                {"version": "1.2.0", "archive": "../evil.tar.gz", "sha256": "cc"}
+            ]}}"#,
+        )?;
+
+        let resolved = resolve_from_repository(
+            &repository_path,
+            "robot",
+            &VersionConstraint::Requirement("<1.2".into()),
+            &Some("00".into()),
+        )?;
+        assert_eq!(
+            resolved.archive_path,
+            repository_path.join("robot").join("1.1.0.zip")
+        );
+        assert_eq!(resolved.label.as_deref(), Some("1.1.0"));
+        assert_eq!(
+            resolved.verification_config,
+            Some(ArchiveVerificationConfig {
+                sha256: "bb".into(),
+                signature_config: Some(SignatureConfig {
+                    signature_path: repository_path.join("robot").join("1.1.0.zip.sig"),
+                    public_key: "00".into(),
+                }),
+            })
+        );
+
+        let error =
+            resolve_from_repository(&repository_path, "robot", &VersionConstraint::Latest, &None)
+                .err()
+                .unwrap();
+        assert!(error
+            .to_string()
+            .contains("must be a relative path within the repository"));
+        let error = resolve_from_repository(
+            &repository_path,
+            "other_robot",
+            &VersionConstraint::Latest,
+            &None,
+        )
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Robot other_robot is not listed in"));
+        Ok(())
+    }
+}
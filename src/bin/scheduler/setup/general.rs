@@ -1,6 +1,6 @@
 use super::plans_by_sessions;
 use super::rcc::rcc_setup_working_directory;
-use crate::build::environment_building_working_directory;
+use crate::build::{environment_build_cache_directory, environment_building_working_directory};
 use crate::internal_config::{
     plans_working_directory, sort_plans_by_grouping, GlobalConfig, Plan, Source,
 };
@@ -13,6 +13,9 @@ use robotmk::fs::{create_dir_all, remove_dir_all, remove_file};
 use robotmk::results::{plan_results_directory, SetupFailure};
 use robotmk::termination::Terminate;
 use std::collections::HashSet;
+use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::fs::{set_permissions, Permissions};
 
 pub fn setup(
     global_config: &GlobalConfig,
@@ -33,10 +36,21 @@ pub fn setup(
         plans.iter().map(|plan| &plan.working_directory),
         top_level_directories(&plans_working_directory(&global_config.working_directory))?.iter(),
     )?;
-    if global_config.managed_directory.exists() {
-        remove_dir_all(&global_config.managed_directory)?;
-    }
+    let cache_directory = environment_build_cache_directory(&global_config.working_directory);
+    create_dir_all(&cache_directory)?;
+    clean_up_file_system_entries(
+        plans.iter().map(|plan| cache_directory.join(&plan.id)),
+        top_level_directory_entries(&cache_directory)?,
+    )?;
+    // Git checkouts are kept, such that we can fall back to them if fetching fails.
     create_dir_all(&global_config.managed_directory)?;
+    clean_up_file_system_entries(
+        plans.iter().filter_map(|plan| match &plan.source {
+            Source::Git(git_source) => Some(&git_source.target),
+            _ => None,
+        }),
+        top_level_directory_entries(&global_config.managed_directory)?.iter(),
+    )?;
     setup_results_directories(global_config, &plans)?;
 
     let (surviving_plans, managed_dir_failures) = setup_managed_directories(plans);
@@ -296,14 +310,32 @@ fn setup_with_one_directory_for_current_session(
 fn setup_results_directories(global_config: &GlobalConfig, plans: &[Plan]) -> AnyhowResult<()> {
     create_dir_all(&global_config.results_directory)?;
     create_dir_all(plan_results_directory(&global_config.results_directory))?;
+    create_lock_file(global_config.results_directory_locker.lock_path())?;
     clean_up_results_directory(global_config, plans).context("Failed to clean up results directory")
 }
 
+// The agent plugin only needs to be able to open the lock file for reading.
+fn create_lock_file(path: &Utf8Path) -> AnyhowResult<()> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .context(format!("Failed to create lock file `{path}`"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        set_permissions(path, Permissions::from_mode(0o644))
+            .context(format!("Failed to set permissions of lock file `{path}`"))?;
+    }
+    Ok(())
+}
+
 fn setup_managed_directories(plans: Vec<Plan>) -> (Vec<Plan>, Vec<SetupFailure>) {
     let mut surviving_plans = Vec::new();
     let mut failures = vec![];
     for plan in plans {
-        if let Source::Managed { target, .. } = &plan.source {
+        if let Some(target) = plan.source.managed_target() {
             if let Err(e) = create_dir_all(target) {
                 let error = anyhow!(e);
                 error!(
@@ -359,7 +391,9 @@ fn clean_up_results_directory(
         .results_directory_locker
         .wait_for_write_lock()?;
     for path in top_level_files(&global_config.results_directory)? {
-        remove_file(path)?;
+        if path != global_config.results_directory_locker.lock_path() {
+            remove_file(path)?;
+        }
     }
     clean_up_file_system_entries(
         plans.iter().map(|plan| &plan.results_file),
@@ -0,0 +1,399 @@
+use crate::internal_config::{GitSource, GlobalConfig, Plan, Source};
+use anyhow::{bail, Context};
+use camino::Utf8Path;
+use log::{error, info, warn};
+use robotmk::results::SetupFailure;
+use robotmk::termination::{kill_process_tree, Cancelled};
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use sysinfo::Pid;
+use tokio_util::sync::CancellationToken;
+
+// Generous, since fetching large repositories takes a while. The point is to never hang forever,
+// e.g. on an unresponsive server.
+const GIT_TIMEOUT: Duration = Duration::from_secs(600);
+// Most git calls finish quickly, so we start polling at a short interval.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn setup(
+    global_config: &GlobalConfig,
+    plans: Vec<Plan>,
+) -> Result<(Vec<Plan>, Vec<SetupFailure>), Cancelled> {
+    let cancellation_token = &global_config.cancellation_token;
+    let mut surviving_plans = Vec::new();
+    let mut failures = vec![];
+    for mut plan in plans.into_iter() {
+        if let Source::Git(git_source) = &mut plan.source {
+            let result = update_checkout(git_source, cancellation_token);
+            if cancellation_token.is_cancelled() {
+                return Err(Cancelled);
+            }
+            match result {
+                Ok(commit) => {
+                    info!(
+                        "Plan {}: Checked out {} ({commit}) from {}.",
+                        plan.id, git_source.reference, git_source.repository_url
+                    );
+                    git_source.commit = Some(commit);
+                }
+                Err(error) => {
+                    failures.push(SetupFailure {
+                        plan_id: plan.id.clone(),
+                        summary: "Failed to update Git checkout".to_string(),
+                        details: format!("{error:?}"),
+                    });
+                    match current_commit(&git_source.target, cancellation_token) {
+                        Some(commit) => {
+                            warn!(
+                                "Plan {}: Failed to update Git checkout, keeping last good \
+                                 checkout ({commit}).
+                                 Error: {error:?}",
+                                plan.id
+                            );
+                            git_source.commit = Some(commit);
+                        }
+                        None => {
+                            error!(
+                                "Plan {}: Failed to update Git checkout. Plan won't be scheduled.
+                                 Error: {error:?}",
+                                plan.id
+                            );
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+        surviving_plans.push(plan);
+    }
+    Ok((surviving_plans, failures))
+}
+
+// Branches are looked up before tags, commit hashes last. The checkout is only touched once the
+// reference has been fetched and resolved successfully. Pruning makes sure that branches deleted
+// upstream do not resolve to their last known commit.
+fn update_checkout(
+    git_source: &GitSource,
+    cancellation_token: &CancellationToken,
+) -> anyhow::Result<String> {
+    let target = &git_source.target;
+    if target.join(".git").is_dir() {
+        run_git(
+            target,
+            ["remote", "set-url", "origin", &git_source.repository_url],
+            cancellation_token,
+        )?;
+    } else {
+        run_git(target, ["init", "--quiet"], cancellation_token)?;
+        run_git(
+            target,
+            ["remote", "add", "origin", &git_source.repository_url],
+            cancellation_token,
+        )?;
+    }
+    run_git(
+        target,
+        [
+            "fetch",
+            "--quiet",
+            "--force",
+            "--prune",
+            "--tags",
+            "origin",
+            "+refs/heads/*:refs/remotes/origin/*",
+        ],
+        cancellation_token,
+    )
+    .context(format!("Failed to fetch {}", git_source.repository_url))?;
+    let Some(commit) = [
+        format!("refs/remotes/origin/{}", git_source.reference),
+        format!("refs/tags/{}", git_source.reference),
+        git_source.reference.clone(),
+    ]
+    .iter()
+    .find_map(|candidate| resolve_commit(target, candidate, cancellation_token)) else {
+        bail!(
+            "Reference `{}` not found in {}",
+            git_source.reference,
+            git_source.repository_url
+        )
+    };
+    run_git(
+        target,
+        ["checkout", "--quiet", "--force", "--detach", &commit],
+        cancellation_token,
+    )?;
+    run_git(target, ["clean", "--quiet", "-ffdx"], cancellation_token)?;
+    Ok(commit)
+}
+
+fn current_commit(target: &Utf8Path, cancellation_token: &CancellationToken) -> Option<String> {
+    if !target.join(".git").is_dir() {
+        return None;
+    }
+    resolve_commit(target, "HEAD", cancellation_token)
+}
+
+fn resolve_commit(
+    target: &Utf8Path,
+    reference: &str,
+    cancellation_token: &CancellationToken,
+) -> Option<String> {
+    run_git(
+        target,
+        [
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{reference}^{{commit}}"),
+        ],
+        cancellation_token,
+    )
+    .ok()
+}
+
+// Returns the trimmed stdout. Git must never wait for credentials to be entered, neither over HTTP
+// nor over SSH.
+fn run_git<'a>(
+    working_directory: &Utf8Path,
+    arguments: impl IntoIterator<Item = &'a str>,
+    cancellation_token: &CancellationToken,
+) -> anyhow::Result<String> {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(working_directory)
+        .args(arguments)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GIT_SSH_COMMAND", "ssh -oBatchMode=yes")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .context(format!("Calling git failed. Command:\n{command:?}"))?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = wait_for_git(&mut child, cancellation_token)
+        .context(format!("Calling git failed. Command:\n{command:?}"))?;
+    let stdout = String::from_utf8_lossy(&stdout.join().unwrap_or_default()).to_string();
+    let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).to_string();
+    if !status.success() {
+        bail!(
+            "git exited non-successfully.\n\nCommand:\n{command:?}\n\nStdout:\n{stdout}\n\nStderr:\n{stderr}"
+        )
+    }
+    Ok(stdout.trim().to_string())
+}
+
+// Reading in separate threads prevents git from blocking on a full pipe while we wait for it.
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    spawn(move || {
+        let mut buffer = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+fn wait_for_git(
+    child: &mut Child,
+    cancellation_token: &CancellationToken,
+) -> anyhow::Result<ExitStatus> {
+    let start = Instant::now();
+    let mut poll_interval = MIN_POLL_INTERVAL;
+    loop {
+        if cancellation_token.is_cancelled() {
+            kill_git(child);
+            bail!("Cancelled")
+        }
+        if start.elapsed() > GIT_TIMEOUT {
+            kill_git(child);
+            bail!("Timed out after {GIT_TIMEOUT:?}")
+        }
+        if let Some(status) = child.try_wait().context("Failed to wait for git")? {
+            return Ok(status);
+        }
+        sleep(poll_interval);
+        poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+// Also covers helpers such as ssh, which would otherwise keep the pipes open.
+fn kill_git(child: &mut Child) {
+    kill_process_tree(&Pid::from_u32(child.id()));
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf;
+    use std::fs::{create_dir, read_to_string, write};
+    use tempfile::tempdir;
+
+    fn commit_file(work_tree: &Utf8Path, content: &str) -> anyhow::Result<String> {
+        write(work_tree.join("tasks.robot"), content)?;
+        run_git(work_tree, ["add", "tasks.robot"], &CancellationToken::new())?;
+        run_git(
+            work_tree,
+            [
+                "-c",
+                "user.name=Robotmk",
+                "-c",
+                "user.email=robotmk@example.com",
+                "commit",
+                "--quiet",
+                "--message",
+                content,
+            ],
+            &CancellationToken::new(),
+        )?;
+        run_git(work_tree, ["rev-parse", "HEAD"], &CancellationToken::new())
+    }
+
+    // Sets up a bare repository with two commits on `main`, the first one being tagged `v1`.
+    fn bare_repository(path: &Utf8Path) -> anyhow::Result<(Utf8PathBuf, String, String)> {
+        let bare = path.join("bare.git");
+        let work_tree = path.join("work_tree");
+        create_dir(&bare)?;
+        create_dir(&work_tree)?;
+        run_git(
+            &bare,
+            ["init", "--quiet", "--bare"],
+            &CancellationToken::new(),
+        )?;
+        run_git(
+            &work_tree,
+            ["init", "--quiet", "--initial-branch", "main"],
+            &CancellationToken::new(),
+        )?;
+        let first_commit = commit_file(&work_tree, "first")?;
+        run_git(&work_tree, ["tag", "v1"], &CancellationToken::new())?;
+        let second_commit = commit_file(&work_tree, "second")?;
+        run_git(
+            &work_tree,
+            ["push", "--quiet", "--tags", bare.as_str(), "main"],
+            &CancellationToken::new(),
+        )?;
+        Ok((bare, first_commit, second_commit))
+    }
+
+    fn git_source(repository_url: &Utf8Path, reference: &str, target: &Utf8Path) -> GitSource {
+        GitSource {
+            repository_url: repository_url.to_string(),
+            reference: reference.into(),
+            target: target.into(),
+            commit: None,
+        }
+    }
+
+    #[test]
+    fn update_checkout_resolves_references() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let (bare, first_commit, second_commit) = bare_repository(&temp_dir_path)?;
+        let target = temp_dir_path.join("checkout");
+        create_dir(&target)?;
+
+        assert_eq!(
+            update_checkout(
+                &git_source(&bare, "main", &target),
+                &CancellationToken::new()
+            )?,
+            second_commit
+        );
+        assert_eq!(read_to_string(target.join("tasks.robot"))?, "second");
+        assert_eq!(
+            update_checkout(&git_source(&bare, "v1", &target), &CancellationToken::new(),)?,
+            first_commit
+        );
+        assert_eq!(read_to_string(target.join("tasks.robot"))?, "first");
+        assert_eq!(
+            update_checkout(
+                &git_source(&bare, &second_commit, &target),
+                &CancellationToken::new(),
+            )?,
+            second_commit
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_update_keeps_last_good_checkout() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let (bare, first_commit, _) = bare_repository(&temp_dir_path)?;
+        let target = temp_dir_path.join("checkout");
+        create_dir(&target)?;
+        update_checkout(&git_source(&bare, "v1", &target), &CancellationToken::new())?;
+
+        let error = update_checkout(
+            &git_source(&bare, "unknown", &target),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{error:?}").contains("Reference `unknown` not found"));
+        let error = update_checkout(
+            &git_source(&temp_dir_path.join("missing"), "v1", &target),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{error:?}").contains("Failed to fetch"));
+        assert_eq!(
+            current_commit(&target, &CancellationToken::new()),
+            Some(first_commit)
+        );
+        assert_eq!(read_to_string(target.join("tasks.robot"))?, "first");
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_branch_is_not_found() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let (bare, _, second_commit) = bare_repository(&temp_dir_path)?;
+        run_git(
+            &bare,
+            ["branch", "feature", "main"],
+            &CancellationToken::new(),
+        )?;
+        let target = temp_dir_path.join("checkout");
+        create_dir(&target)?;
+        assert_eq!(
+            update_checkout(
+                &git_source(&bare, "feature", &target),
+                &CancellationToken::new(),
+            )?,
+            second_commit
+        );
+
+        run_git(
+            &bare,
+            ["branch", "-D", "feature"],
+            &CancellationToken::new(),
+        )?;
+        let error = update_checkout(
+            &git_source(&bare, "feature", &target),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{error:?}").contains("Reference `feature` not found"));
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_git_call_fails() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let cancellation_token = CancellationToken::new();
+        cancellation_token.cancel();
+        let error = run_git(&temp_dir_path, ["init", "--quiet"], &cancellation_token).unwrap_err();
+        assert!(format!("{error:?}").contains("Cancelled"));
+        Ok(())
+    }
+}
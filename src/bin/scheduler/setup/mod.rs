@@ -1,5 +1,9 @@
+pub mod artifact_repository;
 pub mod general;
+pub mod git;
+pub mod provenance;
 pub mod rcc;
+pub mod resource_limits;
 pub mod unpack_managed;
 pub mod windows_permissions;
 
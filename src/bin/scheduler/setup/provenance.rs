@@ -0,0 +1,262 @@
+use crate::internal_config::{Plan, Source};
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use log::warn;
+use robotmk::environment::Environment;
+use robotmk::results::Provenance;
+use sha2::{Digest, Sha256};
+use std::fs::{read, read_link, read_to_string};
+use walkdir::WalkDir;
+
+pub fn setup(plans: Vec<Plan>) -> Vec<Plan> {
+    plans
+        .into_iter()
+        .map(|mut plan| {
+            plan.provenance = provenance(&plan);
+            plan
+        })
+        .collect()
+}
+
+pub fn provenance(plan: &Plan) -> Provenance {
+    let (source_version, source_hash) = match &plan.source {
+        Source::Manual { base_dir } => (None, logged_directory_hash(&plan.id, base_dir)),
+        Source::Managed(managed_source) => match &managed_source.version {
+            Some(version) => (version.label.clone(), Some(version.sha256.clone())),
+            None => (None, None),
+        },
+        Source::Git(git_source) => (
+            git_source.commit.clone(),
+            logged_directory_hash(&plan.id, &git_source.target),
+        ),
+    };
+    let (environment_hash, holotree_space) = match &plan.environment {
+        Environment::Rcc(rcc_environment) => (
+            environment_hash(&rcc_environment.robot_yaml_path),
+            Some(rcc_environment.space.clone()),
+        ),
+        Environment::System(_) => (None, None),
+    };
+    Provenance {
+        source_kind: plan.provenance.source_kind.clone(),
+        source_version,
+        source_hash,
+        environment_hash,
+        holotree_space,
+    }
+}
+
+// RCC derives the blueprint of a holotree space from `robot.yaml` and the conda configuration it
+// references, hence the hash identifies the environment. Without a hash, callers must assume that
+// the environment changed.
+pub fn environment_hash(robot_yaml_path: &Utf8Path) -> Option<String> {
+    let robot_yaml = read_to_string(robot_yaml_path).ok()?;
+    let robot_directory = robot_yaml_path.parent()?;
+    let mut hasher = Sha256::new();
+    hasher.update(&robot_yaml);
+    let mut any_resolved = false;
+    for config_file in conda_config_files(&robot_yaml) {
+        hasher.update(format!("\0{config_file}\0"));
+        match read(robot_directory.join(config_file)) {
+            Ok(content) => {
+                any_resolved = true;
+                hasher.update(Sha256::digest(content));
+            }
+            Err(_) => hasher.update("absent"),
+        }
+    }
+    any_resolved.then(|| format!("{:x}", hasher.finalize()))
+}
+
+// Collects the block list under `environmentConfigs` and the top-level `condaConfigFile`. RCC picks
+// the first platform-matching, existing file among the `environmentConfigs`, falling back to
+// `condaConfigFile`. Instead of replicating that choice, every candidate is covered, including
+// whether it exists.
+fn conda_config_files(robot_yaml: &str) -> Vec<&str> {
+    let mut config_files = vec![];
+    let mut lines = robot_yaml.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.starts_with("environmentConfigs:") {
+            while let Some(item) = lines
+                .peek()
+                .and_then(|line| line.trim_start().strip_prefix('-'))
+            {
+                config_files.extend(yaml_scalar(item));
+                lines.next();
+            }
+        } else if let Some(value) = line.strip_prefix("condaConfigFile:") {
+            config_files.extend(yaml_scalar(value));
+        }
+    }
+    config_files
+}
+
+fn yaml_scalar(value: &str) -> Option<&str> {
+    value
+        .split(" #")
+        .next()
+        .map(|value| value.trim().trim_matches(|c| c == '"' || c == '\''))
+        .filter(|value| !value.is_empty())
+}
+
+fn logged_directory_hash(plan_id: &str, path: &Utf8Path) -> Option<String> {
+    directory_hash(path)
+        .map_err(|error| warn!("Plan {plan_id}: Failed to hash robot source. Error: {error:?}"))
+        .ok()
+}
+
+// Covers the relative paths, the file contents and the symlink targets. The `.git` directory of Git
+// checkouts is skipped, it does not belong to the robot.
+fn directory_hash(path: &Utf8Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    for entry in WalkDir::new(path)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !(entry.depth() == 1 && entry.file_name() == ".git"))
+    {
+        let entry = entry.context(format!("Failed to walk {path}"))?;
+        let entry_path = Utf8PathBuf::try_from(entry.path().to_path_buf())?;
+        let relative_path = entry_path
+            .strip_prefix(path)?
+            .components()
+            .map(|component| component.as_str())
+            .collect::<Vec<_>>()
+            .join("/");
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            hasher.update(format!("D\0{relative_path}\0"));
+        } else if file_type.is_symlink() {
+            let target = read_link(&entry_path).context(format!("Failed to read {entry_path}"))?;
+            hasher.update(format!("L\0{relative_path}\0{}\0", target.display()));
+        } else {
+            let content = read(&entry_path).context(format!("Failed to read {entry_path}"))?;
+            hasher.update(format!("F\0{relative_path}\0"));
+            hasher.update(Sha256::digest(content));
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn directory_hash_covers_paths_and_content() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        create_dir(path.join("suite"))?;
+        write(path.join("suite").join("tasks.robot"), "content")?;
+        let initial_hash = directory_hash(&path)?;
+
+        create_dir(path.join(".git"))?;
+        write(path.join(".git").join("HEAD"), "ref")?;
+        assert_eq!(directory_hash(&path)?, initial_hash);
+
+        write(path.join("suite").join("tasks.robot"), "changed")?;
+        let changed_hash = directory_hash(&path)?;
+        assert_ne!(changed_hash, initial_hash);
+
+        write(path.join("suite").join("other.robot"), "")?;
+        assert_ne!(directory_hash(&path)?, changed_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn environment_hash_covers_conda_yaml() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let robot_yaml_path = path.join("robot.yaml");
+        assert_eq!(environment_hash(&robot_yaml_path), None);
+
+        write(&robot_yaml_path, "condaConfigFile: conda.yaml")?;
+        write(path.join("conda.yaml"), "python=3.10")?;
+        let initial_hash = environment_hash(&robot_yaml_path);
+        assert!(initial_hash.is_some());
+
+        write(path.join("conda.yaml"), "python=3.12")?;
+        assert_ne!(environment_hash(&robot_yaml_path), initial_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn environment_hash_follows_conda_config_file() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let robot_yaml_path = path.join("robot.yaml");
+        create_dir(path.join("env"))?;
+        write(path.join("conda.yaml"), "python=3.10")?;
+        write(
+            &robot_yaml_path,
+            "tasks:\n  Run:\n    shell: python -m robot tasks.robot\n\
+             condaConfigFile: \"env/conda.yaml\" # pinned\n",
+        )?;
+        assert_eq!(environment_hash(&robot_yaml_path), None);
+
+        write(path.join("env").join("conda.yaml"), "python=3.10")?;
+        let initial_hash = environment_hash(&robot_yaml_path);
+        assert!(initial_hash.is_some());
+        write(path.join("conda.yaml"), "python=3.12")?;
+        assert_eq!(environment_hash(&robot_yaml_path), initial_hash);
+        write(path.join("env").join("conda.yaml"), "python=3.12")?;
+        assert_ne!(environment_hash(&robot_yaml_path), initial_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn environment_hash_covers_environment_configs() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let robot_yaml_path = path.join("robot.yaml");
+        write(
+            &robot_yaml_path,
+            "tasks:\n  Run:\n    shell: python -m robot tasks.robot\n\n\
+             environmentConfigs:\n  \
+             - environment_windows_amd64_freeze.yaml\n  \
+             - environment_linux_amd64_freeze.yaml\n  \
+             - environment_darwin_amd64_freeze.yaml\n  \
+             - conda.yaml\n\n\
+             artifactsDir: output\n",
+        )?;
+        assert_eq!(environment_hash(&robot_yaml_path), None);
+
+        write(path.join("conda.yaml"), "python=3.10")?;
+        let initial_hash = environment_hash(&robot_yaml_path);
+        assert!(initial_hash.is_some());
+        assert_eq!(environment_hash(&robot_yaml_path), initial_hash);
+
+        write(
+            path.join("environment_linux_amd64_freeze.yaml"),
+            "python=3.10.12",
+        )?;
+        let frozen_hash = environment_hash(&robot_yaml_path);
+        assert!(frozen_hash.is_some());
+        assert_ne!(frozen_hash, initial_hash);
+
+        write(
+            path.join("environment_linux_amd64_freeze.yaml"),
+            "python=3.10.13",
+        )?;
+        assert_ne!(environment_hash(&robot_yaml_path), frozen_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn conda_config_files_are_collected() {
+        assert_eq!(
+            conda_config_files("condaConfigFile: conda.yaml"),
+            vec!["conda.yaml"]
+        );
+        assert_eq!(
+            conda_config_files(
+                "environmentConfigs:\n  - \"conda_linux.yaml\" # frozen\n  - conda.yaml\n\
+                 condaConfigFile: other.yaml"
+            ),
+            vec!["conda_linux.yaml", "conda.yaml", "other.yaml"]
+        );
+        assert!(conda_config_files("condaConfigFile:\nenvironmentConfigs:\n").is_empty());
+    }
+}
@@ -208,6 +208,8 @@ fn holotree_disable_sharing(
                 .join(session.id())
                 .join(name),
             timeout: 120,
+            resource_limits_config: None,
+            termination_config: None,
             cancellation_token: &global_config.cancellation_token,
         };
         match session.run(run_spec) {
@@ -296,6 +298,8 @@ fn run_command_spec_once_in_current_session(
                 command_spec,
                 base_path,
                 timeout: 120,
+                resource_limits_config: None,
+                termination_config: None,
                 cancellation_token: &global_config.cancellation_token,
             },
         )? {
@@ -342,6 +346,8 @@ fn run_command_spec_per_session(
                 command_spec,
                 base_path,
                 timeout: 120,
+                resource_limits_config: None,
+                termination_config: None,
                 cancellation_token: &global_config.cancellation_token,
             },
         )? {
@@ -0,0 +1,47 @@
+use crate::internal_config::{sort_plans_by_grouping, Plan};
+use anyhow::Result as AnyhowResult;
+use log::{error, info};
+use robotmk::results::SetupFailure;
+
+pub fn setup(plans: Vec<Plan>) -> (Vec<Plan>, Vec<SetupFailure>) {
+    let (limited_plans, mut surviving_plans): (Vec<Plan>, Vec<Plan>) = plans
+        .into_iter()
+        .partition(|plan| plan.resource_limits_config.is_some());
+    if limited_plans.is_empty() {
+        return (surviving_plans, vec![]);
+    }
+    match prepare_hierarchy() {
+        Ok(()) => {
+            info!("Prepared cgroup hierarchy for resource limits");
+            surviving_plans.extend(limited_plans);
+            sort_plans_by_grouping(&mut surviving_plans);
+            (surviving_plans, vec![])
+        }
+        Err(error) => {
+            let mut failures = vec![];
+            for plan in limited_plans {
+                error!(
+                    "Plan {}: Failed to set up resource limits. Plan won't be scheduled.
+                     Error: {error:?}",
+                    plan.id
+                );
+                failures.push(SetupFailure {
+                    plan_id: plan.id.clone(),
+                    summary: "Failed to set up resource limits".to_string(),
+                    details: format!("{error:?}"),
+                });
+            }
+            (surviving_plans, failures)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn prepare_hierarchy() -> AnyhowResult<()> {
+    robotmk::cgroup::prepare_hierarchy()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn prepare_hierarchy() -> AnyhowResult<()> {
+    anyhow::bail!("Resource limits are only supported on Linux")
+}
@@ -1,91 +1,543 @@
-use crate::internal_config::{Plan, Source};
-use anyhow::Context;
-use camino::Utf8Path;
+use super::artifact_repository::{resolve, ResolvedArchive};
+use crate::internal_config::{ArchiveVersion, ManagedSource, Plan, Source};
+use anyhow::{anyhow, bail, Context};
+use camino::{Utf8Path, Utf8PathBuf};
+use ed25519_dalek::{Signature, VerifyingKey};
 use flate2::read::GzDecoder;
-use log::{error, info};
-use robotmk::results::SetupFailure;
-use std::fs::File;
-use tar::Archive;
+use log::{error, info, warn};
+use robotmk::config::{ArchiveVerificationConfig, SignatureConfig};
+use robotmk::fs::{create_dir_all, remove_dir_all, remove_file};
+use robotmk::results::{ManagedRobot, ManagedRobots, SetupFailure};
+use sha2::{Digest, Sha256};
+use std::fs::{copy, metadata, read, read_dir, File};
+use std::io::{self, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tar::{Archive, Entry, EntryType};
+use xz2::read::XzDecoder;
+use zip::read::ZipFile;
+use zip::ZipArchive;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
-const SIZE_LIMIT: u64 = 50 * 1024 * 1024;
+// File type bits of Unix modes, as stored in zip archives created on Unix.
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+
+struct ExtractionLimits {
+    size: u64,
+    n_entries: usize,
+    compression_ratio: u64,
+    // What is left of the quota of the managed directory, if configured
+    quota: Option<u64>,
+}
+
+// The size limit can be overridden globally and per plan.
+const LIMITS: ExtractionLimits = ExtractionLimits {
+    size: 50 * 1024 * 1024,
+    n_entries: 10000,
+    compression_ratio: 200,
+    quota: None,
+};
 
 pub fn setup(plans: Vec<Plan>) -> (Vec<Plan>, Vec<SetupFailure>) {
     let mut surviving_plans = Vec::new();
     let mut failures = vec![];
-    for plan in plans.into_iter() {
-        if let Source::Managed {
-            tar_gz_path,
-            target,
-        } = &plan.source
-        {
-            if let Err(error) = unpack_into(tar_gz_path, target, SIZE_LIMIT) {
-                error!(
-                    "Plan {}: Failed to unpack managed source archive. Plan won't be scheduled.
-                     Error: {error:?}",
-                    plan.id
-                );
-                failures.push(SetupFailure {
-                    plan_id: plan.id.clone(),
-                    summary: "Failed to unpack managed source archive".to_string(),
-                    details: format!("{error:?}"),
-                });
-                continue;
+    for mut plan in plans.into_iter() {
+        if let Source::Managed(managed_source) = &plan.source {
+            let last_modified = modification_time(&managed_source.watched_path()).ok();
+            // During setup, no version is in use yet, so we always expect a new one.
+            let version = match unpack_version(managed_source).and_then(|version| {
+                version.ok_or_else(|| {
+                    UnpackingFailure::unpacking(anyhow!(
+                        "Archive matches the version in use, but none is unpacked yet"
+                    ))
+                })
+            }) {
+                Ok(version) => version,
+                Err(failure) => {
+                    error!(
+                        "Plan {}: {}. Plan won't be scheduled.
+                         Error: {:?}",
+                        plan.id, failure.summary, failure.error
+                    );
+                    failures.push(SetupFailure {
+                        plan_id: plan.id.clone(),
+                        summary: failure.summary.to_string(),
+                        details: format!("{:?}", failure.error),
+                    });
+                    continue;
+                }
+            };
+            info!(
+                "Plan {}: Unpacked version {} into `{}` ({} B).",
+                plan.id,
+                version.label.as_deref().unwrap_or(&version.sha256),
+                version.source_dir,
+                version.unpacked_size
+            );
+            plan.switch_source_dir(&version.source_dir);
+            if let Source::Managed(managed_source) = &mut plan.source {
+                managed_source.version = Some(version);
+                managed_source.last_modified = last_modified;
             }
-            info!("Unpacked {} into `{}`.", tar_gz_path, target);
         }
         surviving_plans.push(plan);
     }
     (surviving_plans, failures)
 }
 
+pub fn managed_robots(plans: &[Plan]) -> ManagedRobots {
+    ManagedRobots(
+        plans
+            .iter()
+            .filter_map(|plan| match &plan.source {
+                Source::Managed(managed_source) => {
+                    managed_source.version.as_ref().map(|version| ManagedRobot {
+                        plan_id: plan.id.clone(),
+                        sha256: version.sha256.clone(),
+                        unpacked_size: version.unpacked_size,
+                        size_limit: managed_source.size_limit.unwrap_or(LIMITS.size),
+                    })
+                }
+                Source::Manual { .. } | Source::Git(_) => None,
+            })
+            .collect(),
+    )
+}
+
+pub struct UnpackingFailure {
+    pub summary: &'static str,
+    pub error: anyhow::Error,
+}
+
+impl UnpackingFailure {
+    fn unpacking(error: anyhow::Error) -> Self {
+        Self {
+            summary: "Failed to unpack managed source archive",
+            error,
+        }
+    }
+}
+
+pub fn modification_time(path: &Utf8Path) -> anyhow::Result<SystemTime> {
+    metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .context(format!("Failed to read modification time of {path}"))
+}
+
+// Every version of an archive is unpacked into its own directory below the target, named after the
+// digest of the archive. Hence, unpacking a new version never touches the version currently in
+// use. To make sure that the archive does not change while we verify and unpack it, we work on a
+// snapshot. Returns `None` if the digest matches the one of the current version.
+pub fn unpack_version(
+    managed_source: &ManagedSource,
+) -> Result<Option<ArchiveVersion>, UnpackingFailure> {
+    let resolved_archive = resolve(&managed_source.origin).map_err(|error| UnpackingFailure {
+        summary: "Failed to resolve managed source archive",
+        error,
+    })?;
+    let limits = extraction_limits(managed_source).map_err(UnpackingFailure::unpacking)?;
+    let archive_path = &resolved_archive.archive_path;
+    let snapshot_path = managed_source.target.join("archive.snapshot");
+    let result = copy(archive_path, &snapshot_path)
+        .context(format!("Failed to copy {archive_path} to {snapshot_path}"))
+        .map_err(UnpackingFailure::unpacking)
+        .and_then(|_| unpack_snapshot(&snapshot_path, managed_source, resolved_archive, &limits));
+    if snapshot_path.exists() {
+        if let Err(error) = remove_file(&snapshot_path) {
+            warn!("{error:?}");
+        }
+    }
+    result
+}
+
+// The quota has to accommodate the new version next to all others. The current version of this
+// plan does not count, since it is discarded once the new version is in use.
+fn extraction_limits(managed_source: &ManagedSource) -> anyhow::Result<ExtractionLimits> {
+    let quota = match managed_source.managed_directory_quota {
+        Some(quota) => {
+            let in_use = directory_size(&managed_source.managed_directory)?.saturating_sub(
+                managed_source
+                    .version
+                    .as_ref()
+                    .map_or(0, |version| version.unpacked_size),
+            );
+            Some(quota.saturating_sub(in_use))
+        }
+        None => None,
+    };
+    Ok(ExtractionLimits {
+        size: managed_source.size_limit.unwrap_or(LIMITS.size),
+        quota,
+        ..LIMITS
+    })
+}
+
+fn unpack_snapshot(
+    snapshot_path: &Utf8Path,
+    managed_source: &ManagedSource,
+    resolved_archive: ResolvedArchive,
+    limits: &ExtractionLimits,
+) -> Result<Option<ArchiveVersion>, UnpackingFailure> {
+    let sha256 = sha256_digest(snapshot_path).map_err(UnpackingFailure::unpacking)?;
+    if managed_source
+        .version
+        .as_ref()
+        .is_some_and(|version| version.sha256 == sha256)
+    {
+        return Ok(None);
+    }
+    if let Some(verification_config) = &resolved_archive.verification_config {
+        verify(snapshot_path, verification_config).map_err(|error| UnpackingFailure {
+            summary: "Failed to verify managed source archive",
+            error,
+        })?;
+        info!("Verified {}.", snapshot_path);
+    }
+    let source_dir = managed_source.target.join(&sha256[..16]);
+    let unpacked = if source_dir.exists() {
+        remove_dir_all(&source_dir)
+    } else {
+        Ok(())
+    }
+    .and_then(|_| unpack_into(snapshot_path, &source_dir, limits))
+    .and_then(|_| directory_size(&source_dir));
+    match unpacked {
+        Ok(unpacked_size) => Ok(Some(ArchiveVersion {
+            label: resolved_archive.label,
+            sha256,
+            source_dir,
+            unpacked_size,
+        })),
+        Err(error) => {
+            if source_dir.exists() {
+                let _ = remove_dir_all(&source_dir);
+            }
+            Err(UnpackingFailure::unpacking(error))
+        }
+    }
+}
+
+// Symlinks are not followed, they are only accounted for with their own size.
+fn directory_size(path: &Utf8Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in read_dir(path).context(format!("Failed to read dir `{path}`"))? {
+        let entry = entry.context(format!("Failed to read entry of `{path}`"))?;
+        let file_type = entry.file_type()?;
+        size += if file_type.is_dir() {
+            let entry_path = Utf8PathBuf::try_from(entry.path())?;
+            directory_size(&entry_path)?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
+
+fn sha256_digest(path: &Utf8Path) -> anyhow::Result<String> {
+    Ok(format!(
+        "{:x}",
+        Sha256::digest(read(path).context(format!("Failed to read {path}"))?)
+    ))
+}
+
+fn verify(
+    archive_path: &Utf8Path,
+    verification_config: &ArchiveVerificationConfig,
+) -> anyhow::Result<()> {
+    let archive = read(archive_path).context(format!("Failed to read {archive_path}"))?;
+    let digest = format!("{:x}", Sha256::digest(&archive));
+    if !digest.eq_ignore_ascii_case(&verification_config.sha256) {
+        bail!(
+            "SHA-256 digest mismatch: expected {}, got {digest}",
+            verification_config.sha256
+        )
+    }
+    if let Some(signature_config) = &verification_config.signature_config {
+        verify_signature(&archive, signature_config)?;
+    }
+    Ok(())
+}
+
+fn verify_signature(archive: &[u8], signature_config: &SignatureConfig) -> anyhow::Result<()> {
+    let public_key: [u8; 32] = hex::decode(&signature_config.public_key)
+        .context("Public key is not hex-encoded")?
+        .try_into()
+        .map_err(|_| anyhow!("Public key must be 32 bytes long"))?;
+    let verifying_key = VerifyingKey::from_bytes(&public_key).context("Invalid public key")?;
+    let signature_path = &signature_config.signature_path;
+    let signature = Signature::from_slice(
+        &read(signature_path).context(format!("Failed to read {signature_path}"))?,
+    )
+    .context(format!(
+        "{signature_path} does not contain an ed25519 signature"
+    ))?;
+    verifying_key
+        .verify_strict(archive, &signature)
+        .context("Signature verification failed")
+}
+
+#[derive(Debug, PartialEq)]
+enum ArchiveFormat {
+    Zip,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+// The format is detected by the magic bytes, file extensions are not reliable.
+fn detect_format(archive_path: &Utf8Path) -> anyhow::Result<ArchiveFormat> {
+    let mut magic = Vec::with_capacity(6);
+    File::open(archive_path)
+        .context(format!("Failed to open {archive_path}"))?
+        .take(6)
+        .read_to_end(&mut magic)
+        .context(format!("Failed to read {archive_path}"))?;
+    Ok(match magic.as_slice() {
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => ArchiveFormat::Zip,
+        [0x1f, 0x8b, ..] => ArchiveFormat::TarGz,
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00] => ArchiveFormat::TarXz,
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => ArchiveFormat::TarZst,
+        _ => bail!("Unsupported archive format, expected zip, tar.gz, tar.xz or tar.zst"),
+    })
+}
+
 fn unpack_into(
-    tar_gz_path: &Utf8Path,
+    archive_path: &Utf8Path,
     target_path: &Utf8Path,
-    size_limit: u64,
+    limits: &ExtractionLimits,
 ) -> anyhow::Result<()> {
-    info!("Extracting archive \"{tar_gz_path}\"");
+    let format = detect_format(archive_path)?;
+    info!("Extracting archive \"{archive_path}\" ({format:?})");
+    let compressed_size = metadata(archive_path)
+        .context(format!("Failed to read metadata of {archive_path}"))?
+        .len();
+    if format == ArchiveFormat::Zip {
+        return unpack_zip_into(archive_path, target_path, compressed_size, limits);
+    }
     // We have to open the archive twice. Re-using the already opened archive for extraction does
     // not work.
-    let archive_size = sum_up_size_of_archive_entries(&mut open_tar_gz_archive(tar_gz_path)?)
-        .context("Failed to compute archive size")?;
-    if archive_size > size_limit {
-        anyhow::bail!(format!(
-            "Archive size exceeds limit: {archive_size} B > {size_limit} B"
-        ))
-    }
-    open_tar_gz_archive(tar_gz_path)?.unpack(target_path)?;
+    inspect_archive(
+        &mut open_tar_archive(archive_path, &format)?,
+        compressed_size,
+        limits,
+    )
+    .context("Archive rejected")?;
+    create_dir_all(target_path)?;
+    for entry in open_tar_archive(archive_path, &format)?.entries()? {
+        entry?.unpack_in(target_path)?;
+    }
     Ok(())
 }
 
-fn open_tar_gz_archive(path: &Utf8Path) -> std::io::Result<Archive<GzDecoder<File>>> {
-    let tar_gz = File::open(path)?;
-    let tar = GzDecoder::new(tar_gz);
+fn open_tar_archive(
+    path: &Utf8Path,
+    format: &ArchiveFormat,
+) -> anyhow::Result<Archive<Box<dyn Read>>> {
+    let file = File::open(path).context(format!("Failed to open {path}"))?;
+    let tar: Box<dyn Read> = match format {
+        ArchiveFormat::TarGz => Box::new(GzDecoder::new(file)),
+        ArchiveFormat::TarXz => Box::new(XzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(ZstdDecoder::new(file)?),
+        ArchiveFormat::Zip => bail!("Zip archives do not contain a tar archive"),
+    };
     Ok(Archive::new(tar))
 }
 
-fn sum_up_size_of_archive_entries<R: Sized + std::io::Read>(
+fn unpack_zip_into(
+    archive_path: &Utf8Path,
+    target_path: &Utf8Path,
+    compressed_size: u64,
+    limits: &ExtractionLimits,
+) -> anyhow::Result<()> {
+    let mut archive = ZipArchive::new(
+        File::open(archive_path).context(format!("Failed to open {archive_path}"))?,
+    )
+    .context(format!("Failed to read zip archive {archive_path}"))?;
+    inspect_zip_archive(&mut archive, compressed_size, limits).context("Archive rejected")?;
+    create_dir_all(target_path)?;
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+        let path = target_path.join(file.name());
+        if file.is_dir() {
+            create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        // The declared size was checked, but the content might be larger.
+        let size = file.size();
+        io::copy(
+            &mut file.take(size),
+            &mut File::create(&path).context(format!("Failed to create {path}"))?,
+        )
+        .context(format!("Failed to extract {path}"))?;
+    }
+    Ok(())
+}
+
+fn inspect_zip_archive<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    compressed_size: u64,
+    limits: &ExtractionLimits,
+) -> anyhow::Result<()> {
+    if archive.len() > limits.n_entries {
+        bail!("Archive contains more than {} entries", limits.n_entries)
+    }
+    let mut size: u64 = 0;
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+        inspect_zip_entry(&file).context(format!("Entry `{}` rejected", file.name()))?;
+        size += file.size();
+        check_unpacked_size(size, compressed_size, limits)?;
+    }
+    Ok(())
+}
+
+fn inspect_zip_entry(file: &ZipFile) -> anyhow::Result<()> {
+    inspect_path(Path::new(file.name()), &[])?;
+    match file.unix_mode().map(|mode| mode & S_IFMT) {
+        Some(S_IFLNK) => bail!("Symlinks are not supported in zip archives"),
+        Some(S_IFCHR | S_IFBLK | S_IFIFO) => bail!("Device files are not allowed"),
+        _ => Ok(()),
+    }
+}
+
+fn check_unpacked_size(
+    size: u64,
+    compressed_size: u64,
+    limits: &ExtractionLimits,
+) -> anyhow::Result<()> {
+    if size > limits.size {
+        bail!("Archive size exceeds limit: {size} B > {} B", limits.size)
+    }
+    if let Some(quota) = limits.quota {
+        if size > quota {
+            bail!("Archive size exceeds remaining quota of managed directory: {size} B > {quota} B")
+        }
+    }
+    if size > compressed_size.saturating_mul(limits.compression_ratio) {
+        bail!(
+            "Compression ratio exceeds limit: {size} B unpacked from {compressed_size} B > {}",
+            limits.compression_ratio
+        )
+    }
+    Ok(())
+}
+
+// Checks all entries before anything is written to disk. The limits are enforced while iterating,
+// such that we stop decompressing as soon as an archive turns out to be a decompression bomb.
+fn inspect_archive<R: Read>(
     archive: &mut Archive<R>,
-) -> anyhow::Result<u64> {
-    let mut sum = 0;
-    for entry in archive.entries()? {
-        let entry_size = entry?.size();
-        // protect against attempts to fake the size
-        if entry_size > 0 {
-            sum += entry_size;
+    compressed_size: u64,
+    limits: &ExtractionLimits,
+) -> anyhow::Result<()> {
+    let mut size: u64 = 0;
+    let mut symlinks = vec![];
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= limits.n_entries {
+            bail!("Archive contains more than {} entries", limits.n_entries)
+        }
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        inspect_entry(&entry, &path, &mut symlinks)
+            .context(format!("Entry `{}` rejected", path.display()))?;
+        size += entry.size();
+        check_unpacked_size(size, compressed_size, limits)?;
+    }
+    Ok(())
+}
+
+fn inspect_entry<R: Read>(
+    entry: &Entry<R>,
+    path: &Path,
+    symlinks: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    inspect_path(path, symlinks)?;
+    match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+        EntryType::Symlink => {
+            let target = entry.link_name()?.context("Symlink has no target")?;
+            inspect_symlink_target(path, &target)?;
+            symlinks.push(path.to_path_buf());
+        }
+        EntryType::Link => {
+            let target = entry.link_name()?.context("Hard link has no target")?;
+            inspect_path(&target, symlinks)
+                .context(format!("Hard link target `{}` rejected", target.display()))?;
         }
+        EntryType::Char | EntryType::Block | EntryType::Fifo => {
+            bail!("Device files are not allowed")
+        }
+        entry_type => bail!("Unsupported entry type {entry_type:?}"),
     }
-    Ok(sum)
+    Ok(())
+}
+
+// Entries below symlinks are rejected, otherwise they could be written through a symlink.
+fn inspect_path(path: &Path, symlinks: &[PathBuf]) -> anyhow::Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir => bail!("Parent directory references (`..`) are not allowed"),
+            Component::RootDir | Component::Prefix(_) => bail!("Absolute paths are not allowed"),
+        }
+    }
+    if let Some(symlink) = symlinks.iter().find(|symlink| path.starts_with(symlink)) {
+        bail!("Path traverses symlink `{}`", symlink.display())
+    }
+    Ok(())
+}
+
+// Symlink targets may only go up (`..`) before descending, and not above the archive root. Going
+// up after descending could resolve through another symlink.
+fn inspect_symlink_target(path: &Path, target: &Path) -> anyhow::Result<()> {
+    let mut depth = path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .count()
+        .saturating_sub(1);
+    let mut descended = false;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(_) => descended = true,
+            Component::ParentDir if descended => bail!(
+                "Symlink target `{}` contains `..` after a directory name",
+                target.display()
+            ),
+            Component::ParentDir if depth == 0 => bail!(
+                "Symlink target `{}` points outside of the archive",
+                target.display()
+            ),
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("Symlink target `{}` is absolute", target.display())
+            }
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use camino::Utf8PathBuf;
+    use crate::internal_config::ArchiveOrigin;
+    use ed25519_dalek::{Signer, SigningKey};
     use flate2::write::GzEncoder;
     use flate2::Compression;
+    use robotmk::config::{EnvironmentConfig, RobotConfig};
     use std::fs;
     use std::io::{self, Write};
+    use tar::Header;
     use tempfile::tempdir;
+    use xz2::write::XzEncoder;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
 
     #[test]
     fn unpack_into_ok() -> anyhow::Result<()> {
@@ -99,7 +551,14 @@ mod tests {
 
         let archive_path = temp_dir_path.join("archive.tar.gz");
         archive_directory(&dir_to_be_archived, &archive_path, "archived")?;
-        unpack_into(&archive_path, &temp_dir_path, 1024)?;
+        unpack_into(
+            &archive_path,
+            &temp_dir_path,
+            &ExtractionLimits {
+                size: 1024,
+                ..LIMITS
+            },
+        )?;
 
         assert_eq!(
             String::from_utf8(fs::read(temp_dir_path.join("archived").join("file.txt"))?)?,
@@ -120,12 +579,368 @@ mod tests {
 
         let archive_path = temp_dir_path.join("archive.tar.gz");
         archive_directory(&dir_to_be_archived, &archive_path, "archived")?;
-        let error = unpack_into(&archive_path, &temp_dir_path, 1).unwrap_err();
+        let error = unpack_into(
+            &archive_path,
+            &temp_dir_path,
+            &ExtractionLimits { size: 1, ..LIMITS },
+        )
+        .unwrap_err();
         assert!(format!("{error:?}").contains("Archive size exceeds limit: 6 B > 1 B"));
 
         Ok(())
     }
 
+    fn managed_source(managed_directory: &Utf8Path, quota: u64) -> ManagedSource {
+        ManagedSource {
+            origin: ArchiveOrigin::File {
+                archive_path: managed_directory.join("..").join("archive.tar.gz"),
+                verification_config: None,
+            },
+            target: managed_directory.join("plan"),
+            size_limit: None,
+            managed_directory: managed_directory.into(),
+            managed_directory_quota: Some(quota),
+            robot_config: RobotConfig {
+                robot_target: "tasks.robot".into(),
+                top_level_suite_name: None,
+                suites: vec![],
+                tests: vec![],
+                test_tags_include: vec![],
+                test_tags_exclude: vec![],
+                variables: vec![],
+                variable_files: vec![],
+                argument_files: vec![],
+                exit_on_failure: false,
+            },
+            environment_config: EnvironmentConfig::System,
+            rcc_binary_path: "/bin/rcc".into(),
+            version: None,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn unpack_version_respects_quota() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let managed_directory = temp_dir_path.join("managed");
+        fs::create_dir_all(managed_directory.join("plan"))?;
+        fs::create_dir_all(managed_directory.join("other_plan"))?;
+        fs::write(managed_directory.join("other_plan/file.txt"), b"1234")?;
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&tar_archive(b"123abc"))?;
+        fs::write(temp_dir_path.join("archive.tar.gz"), encoder.finish()?)?;
+
+        let failure = unpack_version(&managed_source(&managed_directory, 8))
+            .err()
+            .unwrap();
+        assert!(format!("{:?}", failure.error)
+            .contains("Archive size exceeds remaining quota of managed directory: 6 B > 4 B"));
+        assert_eq!(directory_size(&managed_directory)?, 4);
+
+        let version = unpack_version(&managed_source(&managed_directory, 10))
+            .map_err(|failure| failure.error)?
+            .unwrap();
+        assert_eq!(version.unpacked_size, 6);
+        assert_eq!(
+            version.source_dir,
+            managed_directory.join("plan").join(&version.sha256[..16])
+        );
+        assert_eq!(directory_size(&managed_directory)?, 10);
+        Ok(())
+    }
+
+    fn tar_archive(content: &[u8]) -> Vec<u8> {
+        let mut header = Header::new_gnu();
+        header.set_path("archived/file.txt").unwrap();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut archive_builder = tar::Builder::new(vec![]);
+        archive_builder.append(&header, content).unwrap();
+        archive_builder.into_inner().unwrap()
+    }
+
+    fn assert_unpacked(archive: &[u8], expected_format: ArchiveFormat) -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        // Deliberately misleading extension, the format is detected from the content.
+        let archive_path = temp_dir_path.join("archive.bin");
+        fs::write(&archive_path, archive)?;
+
+        assert_eq!(detect_format(&archive_path)?, expected_format);
+        unpack_into(&archive_path, &temp_dir_path.join("target"), &LIMITS)?;
+        assert_eq!(
+            fs::read_to_string(temp_dir_path.join("target/archived/file.txt"))?,
+            "123abc"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unpack_tar_xz() -> anyhow::Result<()> {
+        let mut encoder = XzEncoder::new(vec![], 6);
+        encoder.write_all(&tar_archive(b"123abc"))?;
+        assert_unpacked(&encoder.finish()?, ArchiveFormat::TarXz)
+    }
+
+    #[test]
+    fn unpack_tar_zst() -> anyhow::Result<()> {
+        assert_unpacked(
+            &zstd::encode_all(tar_archive(b"123abc").as_slice(), 0)?,
+            ArchiveFormat::TarZst,
+        )
+    }
+
+    fn zip_archive(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(io::Cursor::new(vec![]));
+        writer.start_file(name, FileOptions::default()).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn unpack_zip() -> anyhow::Result<()> {
+        assert_unpacked(
+            &zip_archive("archived/file.txt", b"123abc"),
+            ArchiveFormat::Zip,
+        )
+    }
+
+    #[test]
+    fn unpack_zip_rejects_parent_directory_traversal() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.zip");
+        fs::write(&archive_path, zip_archive("../evil.txt", b"bad"))?;
+
+        let error = unpack_into(&archive_path, &temp_dir_path.join("target"), &LIMITS).unwrap_err();
+        assert!(format!("{error:?}").contains("Parent directory references (`..`) are not allowed"));
+        assert!(!temp_dir_path.join("evil.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_unsupported_format() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.tar");
+        fs::write(&archive_path, tar_archive(b"123abc"))?;
+
+        let error = detect_format(&archive_path).unwrap_err();
+        assert!(format!("{error:?}").contains("Unsupported archive format"));
+        Ok(())
+    }
+
+    fn raw_header(name: &[u8], entry_type: EntryType, link_name: &[u8], size: u64) -> Header {
+        // Set the raw fields, since the safe setters refuse to create malicious archives.
+        let mut header = Header::new_gnu();
+        let gnu_header = header.as_gnu_mut().unwrap();
+        gnu_header.name[..name.len()].copy_from_slice(name);
+        gnu_header.linkname[..link_name.len()].copy_from_slice(link_name);
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    }
+
+    fn write_tar_gz(archive_path: &Utf8Path, headers: Vec<(Header, Vec<u8>)>) -> io::Result<()> {
+        let mut archive_builder = tar::Builder::new(GzEncoder::new(
+            File::create(archive_path)?,
+            Compression::default(),
+        ));
+        for (header, data) in headers {
+            archive_builder.append(&header, data.as_slice())?;
+        }
+        archive_builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    fn assert_rejected(headers: Vec<(Header, Vec<u8>)>, limits: &ExtractionLimits, reason: &str) {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf()).unwrap();
+        let archive_path = temp_dir_path.join("archive.tar.gz");
+        write_tar_gz(&archive_path, headers).unwrap();
+        let target_path = temp_dir_path.join("target");
+        fs::create_dir(&target_path).unwrap();
+
+        let error = unpack_into(&archive_path, &target_path, limits).unwrap_err();
+        assert!(format!("{error:?}").contains(reason), "{error:?}");
+        assert_eq!(fs::read_dir(&target_path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unpack_into_rejects_parent_directory_traversal() {
+        assert_rejected(
+            vec![(
+                raw_header(b"../evil.txt", EntryType::Regular, b"", 3),
+                b"bad".to_vec(),
+            )],
+            &LIMITS,
+            "Parent directory references (`..`) are not allowed",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_absolute_path() {
+        assert_rejected(
+            vec![(
+                raw_header(b"/tmp/evil.txt", EntryType::Regular, b"", 3),
+                b"bad".to_vec(),
+            )],
+            &LIMITS,
+            "Absolute paths are not allowed",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_symlink_pointing_outside() {
+        assert_rejected(
+            vec![(
+                raw_header(b"sub/link", EntryType::Symlink, b"../../outside", 0),
+                vec![],
+            )],
+            &LIMITS,
+            "Symlink target `../../outside` points outside of the archive",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_writing_through_symlink() {
+        assert_rejected(
+            vec![
+                (raw_header(b"link", EntryType::Symlink, b"sub", 0), vec![]),
+                (
+                    raw_header(b"link/file.txt", EntryType::Regular, b"", 3),
+                    b"bad".to_vec(),
+                ),
+            ],
+            &LIMITS,
+            "Path traverses symlink `link`",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_device_file() {
+        assert_rejected(
+            vec![(raw_header(b"device", EntryType::Char, b"", 0), vec![])],
+            &LIMITS,
+            "Device files are not allowed",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_too_many_entries() {
+        assert_rejected(
+            vec![
+                (raw_header(b"a.txt", EntryType::Regular, b"", 0), vec![]),
+                (raw_header(b"b.txt", EntryType::Regular, b"", 0), vec![]),
+            ],
+            &ExtractionLimits {
+                n_entries: 1,
+                ..LIMITS
+            },
+            "Archive contains more than 1 entries",
+        );
+    }
+
+    #[test]
+    fn unpack_into_rejects_compression_bomb() {
+        assert_rejected(
+            vec![(
+                raw_header(b"zeros", EntryType::Regular, b"", 1024 * 1024),
+                vec![0; 1024 * 1024],
+            )],
+            &LIMITS,
+            "Compression ratio exceeds limit",
+        );
+    }
+
+    #[test]
+    fn unpack_into_allows_symlink_within_archive() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.tar.gz");
+        write_tar_gz(
+            &archive_path,
+            vec![
+                (
+                    raw_header(b"file.txt", EntryType::Regular, b"", 3),
+                    b"abc".to_vec(),
+                ),
+                (
+                    raw_header(b"sub/link", EntryType::Symlink, b"../file.txt", 0),
+                    vec![],
+                ),
+            ],
+        )?;
+        let target_path = temp_dir_path.join("target");
+        unpack_into(&archive_path, &target_path, &LIMITS)?;
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_to_string(target_path.join("sub").join("link"))?,
+            "abc"
+        );
+        Ok(())
+    }
+
+    fn verification_config(archive: &[u8], signature_path: &Utf8Path) -> ArchiveVerificationConfig {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        fs::write(signature_path, signing_key.sign(archive).to_bytes()).unwrap();
+        ArchiveVerificationConfig {
+            sha256: format!("{:x}", Sha256::digest(archive)),
+            signature_config: Some(SignatureConfig {
+                signature_path: signature_path.into(),
+                public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+            }),
+        }
+    }
+
+    #[test]
+    fn verify_ok() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.tar.gz");
+        fs::write(&archive_path, b"archive")?;
+
+        verify(
+            &archive_path,
+            &verification_config(b"archive", &temp_dir_path.join("archive.sig")),
+        )
+    }
+
+    #[test]
+    fn verify_digest_mismatch() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.tar.gz");
+        fs::write(&archive_path, b"tampered")?;
+
+        let error = verify(
+            &archive_path,
+            &verification_config(b"archive", &temp_dir_path.join("archive.sig")),
+        )
+        .unwrap_err();
+        assert!(format!("{error:?}").contains("SHA-256 digest mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_signature_mismatch() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+        let archive_path = temp_dir_path.join("archive.tar.gz");
+        fs::write(&archive_path, b"tampered")?;
+
+        let mut verification_config =
+            verification_config(b"archive", &temp_dir_path.join("archive.sig"));
+        verification_config.sha256 = format!("{:x}", Sha256::digest(b"tampered"));
+        let error = verify(&archive_path, &verification_config).unwrap_err();
+        assert!(format!("{error:?}").contains("Signature verification failed"));
+        Ok(())
+    }
+
     fn archive_directory(
         dir_to_be_archived: &Utf8Path,
         archive_path: &Utf8Path,
@@ -0,0 +1,234 @@
+use crate::config::ResourceLimitsConfig;
+use crate::results::ResourceLimit;
+
+use anyhow::{bail, Context, Result as AnyhowResult};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::debug;
+use std::fs::{self, File};
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration;
+use tokio::process::Command;
+
+// Name of the leaf cgroup the scheduler process moves itself into. cgroup v2 only allows enabling
+// controllers for the children of a cgroup which does not contain any processes itself ("no
+// internal processes" rule). Hence, we move the scheduler into a dedicated leaf, which turns our
+// original cgroup into the root of the sub-tree in which we create one cgroup per child process.
+const SCHEDULER_LEAF: &str = "robotmk_scheduler";
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+const CPU_PERIOD: u64 = 100000;
+
+pub struct Cgroup {
+    path: Utf8PathBuf,
+}
+
+pub fn prepare_hierarchy() -> AnyhowResult<()> {
+    let current = current_cgroup()?;
+    if current.file_name() == Some(SCHEDULER_LEAF) {
+        debug!("cgroup hierarchy already prepared: {current}");
+        return Ok(());
+    }
+    let leaf = current.join(SCHEDULER_LEAF);
+    if !leaf.exists() {
+        fs::create_dir(&leaf).context(format!("Failed to create cgroup {leaf}"))?;
+    }
+    write_to(&leaf.join("cgroup.procs"), &std::process::id().to_string())?;
+    let available = fs::read_to_string(current.join("cgroup.controllers"))
+        .context(format!("Failed to read available controllers of {current}"))?;
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == controller) {
+            bail!("Controller {controller} is not available in cgroup {current}")
+        }
+        write_to(
+            &current.join("cgroup.subtree_control"),
+            &format!("+{controller}"),
+        )?;
+    }
+    Ok(())
+}
+
+impl Cgroup {
+    pub fn create(name: &str, resource_limits_config: &ResourceLimitsConfig) -> AnyhowResult<Self> {
+        let current = current_cgroup()?;
+        if current.file_name() != Some(SCHEDULER_LEAF) {
+            bail!("cgroup hierarchy has not been prepared, current cgroup is {current}")
+        }
+        let cgroup = Self {
+            path: current
+                .parent()
+                .context(format!("cgroup {current} has no parent"))?
+                .join(name),
+        };
+        if cgroup.path.exists() {
+            debug!("Removing leftover cgroup {}", cgroup.path);
+            cgroup.remove()?;
+        }
+        fs::create_dir(&cgroup.path).context(format!("Failed to create cgroup {}", cgroup.path))?;
+        cgroup.apply_limits(resource_limits_config)?;
+        Ok(cgroup)
+    }
+
+    // The child process moves itself into the cgroup before exec-ing, such that there is no window
+    // in which it could escape the limits by forking.
+    pub fn assign_on_spawn(&self, command: &mut Command) -> AnyhowResult<()> {
+        let procs_path = self.path.join("cgroup.procs");
+        let procs = File::options()
+            .write(true)
+            .open(&procs_path)
+            .context(format!("Failed to open {procs_path}"))?;
+        unsafe {
+            command.pre_exec(move || (&procs).write_all(b"0"));
+        }
+        Ok(())
+    }
+
+    pub fn limit_violation(&self) -> AnyhowResult<Option<ResourceLimit>> {
+        if read_event_count(&self.path.join("memory.events"), "oom_kill")? > 0 {
+            return Ok(Some(ResourceLimit::Memory));
+        }
+        if read_event_count(&self.path.join("pids.events"), "max")? > 0 {
+            return Ok(Some(ResourceLimit::Pids));
+        }
+        Ok(None)
+    }
+
+    // Removing a cgroup requires it to be empty, so we kill whatever is left inside. This includes
+    // processes which escaped the process group of the child.
+    pub fn remove(&self) -> AnyhowResult<()> {
+        let kill_path = self.path.join("cgroup.kill");
+        if kill_path.exists() {
+            write_to(&kill_path, "1")?;
+        }
+        for _ in 0..20 {
+            if fs::read_to_string(self.path.join("cgroup.procs"))
+                .map(|procs| procs.trim().is_empty())
+                .unwrap_or(true)
+            {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        fs::remove_dir(&self.path).context(format!("Failed to remove cgroup {}", self.path))
+    }
+
+    fn apply_limits(&self, resource_limits_config: &ResourceLimitsConfig) -> AnyhowResult<()> {
+        if let Some(memory_max) = resource_limits_config.memory_max {
+            write_to(&self.path.join("memory.max"), &memory_max.to_string())?;
+            write_to(&self.path.join("memory.oom.group"), "1")?;
+        }
+        if let Some(cpu_quota_percent) = resource_limits_config.cpu_quota_percent {
+            write_to(&self.path.join("cpu.max"), &cpu_max(cpu_quota_percent))?;
+        }
+        if let Some(pids_max) = resource_limits_config.pids_max {
+            write_to(&self.path.join("pids.max"), &pids_max.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+fn current_cgroup() -> AnyhowResult<Utf8PathBuf> {
+    let mount_point = parse_cgroup2_mount_point(
+        &fs::read_to_string("/proc/self/mountinfo").context("Failed to read mount info")?,
+    )
+    .context("No cgroup v2 file system mounted")?;
+    let relative_path = parse_cgroup2_path(
+        &fs::read_to_string("/proc/self/cgroup").context("Failed to read own cgroup")?,
+    )
+    .context("Process is not part of a cgroup v2 hierarchy")?;
+    Ok(mount_point.join(relative_path.trim_start_matches('/')))
+}
+
+// See proc(5), /proc/pid/mountinfo. The mount point is the fifth field, the file system type
+// follows after the separator "-".
+fn parse_cgroup2_mount_point(mountinfo: &str) -> Option<Utf8PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let (fields, after_separator) = line.split_once(" - ")?;
+        if after_separator.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        fields.split_whitespace().nth(4).map(Utf8PathBuf::from)
+    })
+}
+
+fn parse_cgroup2_path(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::").map(|path| path.to_string()))
+}
+
+fn parse_event_count(events: &str, key: &str) -> u64 {
+    events
+        .lines()
+        .find_map(|line| {
+            let (name, count) = line.split_once(' ')?;
+            (name == key).then(|| count.trim().parse().ok())?
+        })
+        .unwrap_or(0)
+}
+
+fn read_event_count(path: &Utf8Path, key: &str) -> AnyhowResult<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    Ok(parse_event_count(
+        &fs::read_to_string(path).context(format!("Failed to read {path}"))?,
+        key,
+    ))
+}
+
+fn cpu_max(cpu_quota_percent: u64) -> String {
+    format!("{} {CPU_PERIOD}", cpu_quota_percent * CPU_PERIOD / 100)
+}
+
+fn write_to(path: &Utf8Path, content: &str) -> AnyhowResult<()> {
+    fs::write(path, content).context(format!("Failed to write `{content}` to {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup2_mount_point_hybrid() {
+        let mountinfo = "\
+29 23 0:26 / /sys/fs/cgroup ro,nosuid,nodev,noexec shared:9 - tmpfs tmpfs ro,mode=755
+30 29 0:27 / /sys/fs/cgroup/unified rw,nosuid,nodev,noexec,relatime shared:10 - cgroup2 cgroup2 rw
+31 29 0:28 / /sys/fs/cgroup/memory rw,nosuid,nodev,noexec,relatime shared:11 - cgroup cgroup rw,memory";
+        assert_eq!(
+            parse_cgroup2_mount_point(mountinfo),
+            Some(Utf8PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+    }
+
+    #[test]
+    fn cgroup2_mount_point_missing() {
+        assert_eq!(
+            parse_cgroup2_mount_point(
+                "31 29 0:28 / /sys/fs/cgroup/memory rw,relatime shared:11 - cgroup cgroup rw,memory"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn cgroup2_path() {
+        assert_eq!(
+            parse_cgroup2_path("4:memory:/some/where\n0::/system.slice/robotmk.service\n"),
+            Some("/system.slice/robotmk.service".into())
+        );
+    }
+
+    #[test]
+    fn event_count() {
+        let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 1\noom_group_kill 1\n";
+        assert_eq!(parse_event_count(events, "oom_kill"), 1);
+        assert_eq!(parse_event_count(events, "max"), 12);
+        assert_eq!(parse_event_count(events, "missing"), 0);
+    }
+
+    #[test]
+    fn cpu_max_quota() {
+        assert_eq!(cpu_max(50), "50000 100000");
+        assert_eq!(cpu_max(200), "200000 100000");
+    }
+}
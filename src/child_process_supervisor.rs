@@ -1,9 +1,17 @@
+#[cfg(target_os = "linux")]
+use crate::cgroup::Cgroup;
 use crate::command_spec::CommandSpec;
+use crate::config::{ResourceLimitsConfig, TerminationConfig};
+use crate::orphans::RUN_ID_ENV_VAR;
+use crate::resource_monitor::ResourceMonitor;
+use crate::results::{ResourceLimit, ResourceUsage, TerminatedBy};
 use crate::termination::{kill_process_tree, waited, Outcome};
 
 use anyhow::{Context, Result as AnyhowResult};
 use camino::Utf8PathBuf;
-use log::debug;
+#[cfg(not(target_os = "linux"))]
+use log::warn;
+use log::{debug, error};
 use std::process::{ExitStatus, Stdio};
 use std::time::Duration;
 use sysinfo::Pid;
@@ -11,9 +19,12 @@ use tokio::process::Command;
 use tokio_util::sync::CancellationToken;
 
 pub struct ChildProcessSupervisor<'a> {
+    pub id: &'a str,
     pub command_spec: &'a CommandSpec,
     pub stdio_paths: Option<StdioPaths>,
     pub timeout: u64,
+    pub resource_limits_config: Option<&'a ResourceLimitsConfig>,
+    pub termination_config: Option<&'a TerminationConfig>,
     pub cancellation_token: &'a CancellationToken,
 }
 
@@ -22,8 +33,15 @@ pub struct StdioPaths {
     pub stderr: Utf8PathBuf,
 }
 
+pub struct ChildProcessReport {
+    pub outcome: Outcome<ExitStatus>,
+    pub resource_usage: ResourceUsage,
+    pub resource_limit_violation: Option<ResourceLimit>,
+    pub terminated_by: Option<TerminatedBy>,
+}
+
 impl ChildProcessSupervisor<'_> {
-    pub fn run(&self) -> AnyhowResult<Outcome<ExitStatus>> {
+    pub fn run(&self) -> AnyhowResult<ChildProcessReport> {
         let mut command: Command = self.build_command()?;
 
         let (stdout_path, stderr_path) = if let Some(stdio_paths) = &self.stdio_paths {
@@ -39,15 +57,53 @@ impl ChildProcessSupervisor<'_> {
             self.command_spec,
         );
 
+        match self.resource_limits_config {
+            #[cfg(target_os = "linux")]
+            Some(resource_limits_config) => {
+                self.run_in_cgroup(&mut command, resource_limits_config)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                warn!("Resource limits are only supported on Linux, ignoring them");
+                self.wait_for_child(&mut command)
+            }
+            None => self.wait_for_child(&mut command),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run_in_cgroup(
+        &self,
+        command: &mut Command,
+        resource_limits_config: &ResourceLimitsConfig,
+    ) -> AnyhowResult<ChildProcessReport> {
+        let cgroup = Cgroup::create(self.id, resource_limits_config)
+            .context("Failed to set up cgroup for resource limits")?;
+        cgroup.assign_on_spawn(command)?;
+        let report = self.wait_for_child(command);
+        let resource_limit_violation = cgroup
+            .limit_violation()
+            .context("Failed to check for resource limit violations");
+        if let Err(error) = cgroup.remove() {
+            error!("{error:?}");
+        }
+        let mut report = report?;
+        report.resource_limit_violation = resource_limit_violation?;
+        Ok(report)
+    }
+
+    fn wait_for_child(&self, command: &mut Command) -> AnyhowResult<ChildProcessReport> {
         wait_for_child(
             Duration::from_secs(self.timeout),
             self.cancellation_token,
-            &mut command,
+            &self.termination_config.cloned().unwrap_or_default(),
+            command,
         )
     }
 
     fn build_command(&self) -> AnyhowResult<Command> {
         let mut command = Command::from(self.command_spec);
+        command.env(RUN_ID_ENV_VAR, self.id);
         #[cfg(unix)]
         command.process_group(0);
         if let Some(stdio_paths) = &self.stdio_paths {
@@ -71,33 +127,39 @@ impl ChildProcessSupervisor<'_> {
 async fn wait_for_child(
     duration: Duration,
     flag: &CancellationToken,
+    termination_config: &TerminationConfig,
     command: &mut Command,
-) -> AnyhowResult<Outcome<ExitStatus>> {
+) -> AnyhowResult<ChildProcessReport> {
     let child = &mut command.spawn().context("Failed to spawn subprocess")?;
-    match waited(duration, flag, child.wait()).await {
-        Outcome::Timeout => {
-            #[cfg(windows)]
-            kill_child_tree(child);
-            #[cfg(unix)]
-            interrupt_and_wait(child).await;
-            Ok(Outcome::Timeout)
-        }
-        Outcome::Cancel => {
-            #[cfg(windows)]
-            kill_child_tree(child);
-            #[cfg(unix)]
-            interrupt_and_wait(child).await;
-            Ok(Outcome::Cancel)
-        }
-        Outcome::Completed(result) => {
-            if result.is_err() {
-                kill_child_tree(child);
+    let mut resource_monitor = ResourceMonitor::new(child.id().map(Pid::from_u32));
+    let (outcome, terminated_by) =
+        match waited(duration, flag, resource_monitor.monitor(child.wait())).await {
+            Outcome::Timeout => (
+                Outcome::Timeout,
+                Some(terminate(child, termination_config).await),
+            ),
+            Outcome::Cancel => (
+                Outcome::Cancel,
+                Some(terminate(child, termination_config).await),
+            ),
+            Outcome::Completed(result) => {
+                if result.is_err() {
+                    kill_child_tree(child);
+                }
+                (
+                    Outcome::Completed(
+                        result.context("Failed to retrieve exit status of subprocess")?,
+                    ),
+                    None,
+                )
             }
-            Ok(Outcome::Completed(
-                result.context("Failed to retrieve exit status of subprocess")?,
-            ))
-        }
-    }
+        };
+    Ok(ChildProcessReport {
+        outcome,
+        resource_usage: resource_monitor.usage(),
+        resource_limit_violation: None,
+        terminated_by,
+    })
 }
 
 fn kill_child_tree(child: &tokio::process::Child) {
@@ -106,33 +168,142 @@ fn kill_child_tree(child: &tokio::process::Child) {
     }
 }
 
+#[cfg(windows)]
+async fn terminate(
+    child: &mut tokio::process::Child,
+    _termination_config: &TerminationConfig,
+) -> TerminatedBy {
+    kill_child_tree(child);
+    TerminatedBy::ProcessTreeKill
+}
+
 #[cfg(unix)]
-async fn interrupt_and_wait(child: &mut tokio::process::Child) {
-    use log::error;
+async fn terminate(
+    child: &mut tokio::process::Child,
+    termination_config: &TerminationConfig,
+) -> TerminatedBy {
     use nix::sys::signal::{killpg, Signal};
     use nix::unistd::{getpgid, Pid};
-    use tokio::time::sleep;
+    use tokio::time::timeout;
 
     if let Some(pid) = child.id() {
         match getpgid(Some(Pid::from_raw(pid as i32))) {
             Ok(gid) => {
-                if let Err(error) = killpg(gid, Signal::SIGINT) {
-                    error!("Failed to interrupt process group. Error message:\n{error:?}");
+                for (index, stage) in termination_config.escalation.iter().enumerate() {
+                    debug!("Sending {:?} to process group {gid}", stage.signal);
+                    if let Err(error) = killpg(gid, signal(&stage.signal)) {
+                        error!(
+                            "Failed to send {:?} to process group. Error message:\n{error:?}",
+                            stage.signal
+                        );
+                    }
+                    if timeout(
+                        Duration::from_secs(stage.grace_period),
+                        process_group_terminated(child, gid),
+                    )
+                    .await
+                    .is_ok()
+                    {
+                        return TerminatedBy::Stage {
+                            index,
+                            signal: stage.signal.clone(),
+                        };
+                    }
                 }
+                let _ = killpg(gid, Signal::SIGKILL);
             }
             Err(error) => {
                 error!(
                     "Failed to retrieve process group ID of process {pid}, 
-                     cannot proceed with interruption. Error message:\n{error:?}"
+                     cannot proceed with escalation. Error message:\n{error:?}"
                 );
             }
         }
     }
-    tokio::select! {
-        _ = child.wait() => { },
-        _ = sleep(Duration::from_secs(10)) => {
-            kill_child_tree(child);
-            let _ = child.wait().await;
-        },
-    };
+    kill_child_tree(child);
+    let _ = child.wait().await;
+    TerminatedBy::ProcessTreeKill
+}
+
+// The process group is only empty once our child has been reaped, hence we wait for it first.
+#[cfg(unix)]
+async fn process_group_terminated(child: &mut tokio::process::Child, gid: nix::unistd::Pid) {
+    use nix::sys::signal::killpg;
+    use tokio::time::sleep;
+
+    let _ = child.wait().await;
+    while killpg(gid, None).is_ok() {
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(unix)]
+fn signal(termination_signal: &crate::config::TerminationSignal) -> nix::sys::signal::Signal {
+    use crate::config::TerminationSignal;
+    use nix::sys::signal::Signal;
+
+    match termination_signal {
+        TerminationSignal::Interrupt => Signal::SIGINT,
+        TerminationSignal::Terminate => Signal::SIGTERM,
+        TerminationSignal::Kill => Signal::SIGKILL,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::{TerminationSignal, TerminationStage};
+
+    fn run_ignoring_interrupts(termination_config: &TerminationConfig) -> ChildProcessReport {
+        let mut command_spec = CommandSpec::new("sh");
+        command_spec
+            .add_argument("-c")
+            .add_argument("trap '' INT; sleep 30");
+        ChildProcessSupervisor {
+            id: "test",
+            command_spec: &command_spec,
+            stdio_paths: None,
+            timeout: 1,
+            resource_limits_config: None,
+            termination_config: Some(termination_config),
+            cancellation_token: &CancellationToken::new(),
+        }
+        .run()
+        .unwrap()
+    }
+
+    #[test]
+    fn escalation_to_later_stage() {
+        let report = run_ignoring_interrupts(&TerminationConfig {
+            escalation: vec![
+                TerminationStage {
+                    signal: TerminationSignal::Interrupt,
+                    grace_period: 1,
+                },
+                TerminationStage {
+                    signal: TerminationSignal::Terminate,
+                    grace_period: 5,
+                },
+            ],
+        });
+        assert!(matches!(report.outcome, Outcome::Timeout));
+        assert_eq!(
+            report.terminated_by,
+            Some(TerminatedBy::Stage {
+                index: 1,
+                signal: TerminationSignal::Terminate
+            })
+        );
+    }
+
+    #[test]
+    fn escalation_exhausted() {
+        let report = run_ignoring_interrupts(&TerminationConfig {
+            escalation: vec![TerminationStage {
+                signal: TerminationSignal::Interrupt,
+                grace_period: 1,
+            }],
+        });
+        assert_eq!(report.terminated_by, Some(TerminatedBy::ProcessTreeKill));
+    }
 }
@@ -14,10 +14,23 @@ pub struct Config {
     pub working_directory: Utf8PathBuf,
     pub results_directory: Utf8PathBuf,
     pub managed_directory: Utf8PathBuf,
+    pub managed_robots_config: Option<ManagedRobotsConfig>,
     pub rcc_config: RCCConfig,
+    // Number of environments built at once, one if not set. Builds within a session never overlap.
+    pub environment_build_parallelism: Option<usize>,
+    // Seconds the scheduler waits for the results directory lock before giving up on a write
+    pub results_directory_lock_timeout: Option<u64>,
     pub plan_groups: Vec<SequentialPlanGroup>,
 }
 
+// Sizes are in bytes and refer to the unpacked archives. The size limit applies to every managed
+// robot which does not configure its own limit, the quota to the managed directory as a whole.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManagedRobotsConfig {
+    pub size_limit: Option<u64>,
+    pub quota: Option<u64>,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct RCCConfig {
     pub binary_path: Utf8PathBuf,
@@ -44,8 +57,51 @@ pub struct SequentialPlanGroup {
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Source {
-    Manual { base_dir: Utf8PathBuf },
-    Managed { tar_gz_path: Utf8PathBuf },
+    Manual {
+        base_dir: Utf8PathBuf,
+    },
+    Managed {
+        #[serde(alias = "tar_gz_path")]
+        archive_path: Utf8PathBuf,
+        verification_config: Option<ArchiveVerificationConfig>,
+        size_limit: Option<u64>,
+    },
+    Repository {
+        repository_path: Utf8PathBuf,
+        robot: String,
+        version_constraint: VersionConstraint,
+        public_key: Option<String>,
+        size_limit: Option<u64>,
+    },
+    // The reference is a branch, a tag or a commit hash.
+    Git {
+        repository_url: String,
+        reference: String,
+    },
+}
+
+// A file-based artifact repository contains an index (`index.json`) listing the available versions
+// of every robot. The archives are located in `<repository>/<robot>`. Version requirements use
+// semantic versioning, e.g. `^1.2` or `=1.4.0`. If a public key is configured, each archive must be
+// accompanied by a detached signature (`<archive>.sig`).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum VersionConstraint {
+    Latest,
+    Requirement(String),
+}
+
+// Managed archives are verified before extraction. The digest is hex-encoded. The optional
+// signature is a raw detached ed25519 signature of the archive, the public key is hex-encoded.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ArchiveVerificationConfig {
+    pub sha256: String,
+    pub signature_config: Option<SignatureConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SignatureConfig {
+    pub signature_path: Utf8PathBuf,
+    pub public_key: String,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -58,9 +114,24 @@ pub struct PlanConfig {
     pub session_config: SessionConfig,
     pub working_directory_cleanup_config: WorkingDirectoryCleanupConfig,
     pub host: Host,
+    pub section_config: Option<SectionConfig>,
     pub metadata: PlanMetadata,
 }
 
+// Controls how the results of a plan are transported to the agent. If the section content exceeds
+// the size budget (in bytes, after compression), the HTML log and, if necessary, the XML log are
+// dropped from the rebot result.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SectionConfig {
+    pub compression: Option<SectionCompression>,
+    pub size_budget: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SectionCompression {
+    Gzip,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct RobotConfig {
     pub robot_target: Utf8PathBuf,
@@ -80,6 +151,54 @@ pub struct ExecutionConfig {
     pub n_attempts_max: usize,
     pub retry_strategy: RetryStrategy,
     pub timeout: u64,
+    pub resource_limits_config: Option<ResourceLimitsConfig>,
+    pub termination_config: Option<TerminationConfig>,
+    pub orphan_process_policy: Option<OrphanProcessPolicy>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResourceLimitsConfig {
+    pub memory_max: Option<u64>,
+    pub cpu_quota_percent: Option<u64>,
+    pub pids_max: Option<u64>,
+}
+
+// Signals sent to the process group of a robot which has to be terminated (timeout or
+// cancellation). After each signal, we wait for the grace period before escalating to the next
+// stage. If the process tree is still alive after the last stage, it is killed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TerminationConfig {
+    pub escalation: Vec<TerminationStage>,
+}
+
+impl Default for TerminationConfig {
+    fn default() -> Self {
+        Self {
+            escalation: vec![TerminationStage {
+                signal: TerminationSignal::Interrupt,
+                grace_period: 10,
+            }],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TerminationStage {
+    pub signal: TerminationSignal,
+    pub grace_period: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TerminationSignal {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum OrphanProcessPolicy {
+    Report,
+    Kill,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -1,13 +1,19 @@
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 pub mod child_process_supervisor;
 pub mod command_spec;
 pub mod config;
 pub mod environment;
 pub mod fs;
 pub mod lock;
+pub mod nagios;
+pub mod orphans;
 pub mod plans;
+pub mod resource_monitor;
 pub mod results;
 pub mod rf;
 pub mod section;
 pub mod session;
+pub mod staleness;
 pub mod tasks;
 pub mod termination;
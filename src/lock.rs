@@ -1,25 +1,41 @@
 use crate::termination::Terminate;
 use camino::{Utf8Path, Utf8PathBuf};
 use fs4::FileExt;
-use log::debug;
+use log::{debug, warn};
 use std::fs::File;
 use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::task::{spawn_blocking, JoinError};
 use tokio_util::sync::CancellationToken;
 
+// Waits longer than this are logged as warnings and counted as contended.
+const CONTENTION_THRESHOLD: Duration = Duration::from_secs(5);
+// Bounds for the backoff between attempts to acquire a contended lock.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 #[derive(Clone)]
 pub struct Locker {
     lock_path: Utf8PathBuf,
     cancellation_token: CancellationToken,
+    timeout: Option<Duration>,
+    wait_statistics: Arc<Mutex<LockWaitStatistics>>,
+}
+
+// Shared by all clones of a locker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockWaitStatistics {
+    pub n_waits: usize,
+    pub n_contended_waits: usize,
+    pub longest_wait: Duration,
 }
 
 #[derive(Error, Debug)]
 pub enum LockerError {
     #[error("Failed to open `{0}`")]
     Open(Utf8PathBuf, #[source] io::Error),
-    #[error("Could not complete task")]
-    Join(#[from] JoinError),
     #[error("Terminated")]
     Cancelled,
     #[error("Failed to obtain write lock for `{0}`")]
@@ -28,6 +44,8 @@ pub enum LockerError {
     Shared(Utf8PathBuf, #[source] io::Error),
     #[error("Failed to release lock for `{0}`")]
     Release(Utf8PathBuf, #[source] io::Error),
+    #[error("Timed out after {1:?} while waiting for lock on `{0}`")]
+    Timeout(Utf8PathBuf, Duration),
 }
 
 impl From<LockerError> for Terminate {
@@ -49,57 +67,139 @@ impl Locker {
         Self {
             lock_path: lock_path.as_ref().to_owned(),
             cancellation_token: cancellation_token.cloned().unwrap_or_default(),
+            timeout: None,
+            wait_statistics: Arc::new(Mutex::new(LockWaitStatistics::default())),
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
         }
     }
 
     pub fn wait_for_read_lock(&self) -> Result<Lock, LockerError> {
         debug!("Waiting for read lock");
         let file = self.file()?;
-        let lock_path = self.lock_path.clone();
-        let file = with_cancellation(
-            || {
-                file.lock_shared()
-                    .map(|_| file)
-                    .map_err(|e| LockerError::Shared(lock_path, e))
-            },
-            &self.cancellation_token,
+        self.timed_wait(
+            "read",
+            || FileExt::try_lock_shared(&file),
+            LockerError::Shared,
         )?;
-        debug!("Got read lock");
         Ok(Lock(file, self.lock_path.clone()))
     }
 
     pub fn wait_for_write_lock(&self) -> Result<Lock, LockerError> {
         debug!("Waiting for write lock");
         let file = self.file()?;
-        let lock_path = self.lock_path.clone();
-        let file = with_cancellation(
-            || {
-                file.lock_exclusive()
-                    .map(|_| file)
-                    .map_err(|e| LockerError::Exclusive(lock_path, e))
-            },
-            &self.cancellation_token,
+        self.timed_wait(
+            "write",
+            || FileExt::try_lock_exclusive(&file),
+            LockerError::Exclusive,
         )?;
-        debug!("Got write lock");
         Ok(Lock(file, self.lock_path.clone()))
     }
 
+    pub fn lock_path(&self) -> &Utf8Path {
+        &self.lock_path
+    }
+
+    pub fn wait_statistics(&self) -> LockWaitStatistics {
+        *self.wait_statistics.lock().unwrap()
+    }
+
+    fn timed_wait(
+        &self,
+        kind: &str,
+        try_lock: impl FnMut() -> io::Result<()>,
+        lock_error: fn(Utf8PathBuf, io::Error) -> LockerError,
+    ) -> Result<(), LockerError> {
+        let start = Instant::now();
+        let result =
+            wait_for_lock(try_lock, &self.cancellation_token, self.timeout).map_err(|error| {
+                match error {
+                    WaitError::Lock(error) => lock_error(self.lock_path.clone(), error),
+                    WaitError::Cancelled => LockerError::Cancelled,
+                    WaitError::Timeout(timeout) => {
+                        LockerError::Timeout(self.lock_path.clone(), timeout)
+                    }
+                }
+            });
+        let waited = start.elapsed();
+        if result.is_ok() {
+            debug!("Got {kind} lock after {waited:?}");
+        }
+        let mut wait_statistics = self.wait_statistics.lock().unwrap();
+        wait_statistics.n_waits += 1;
+        wait_statistics.longest_wait = wait_statistics.longest_wait.max(waited);
+        if waited > CONTENTION_THRESHOLD {
+            wait_statistics.n_contended_waits += 1;
+            warn!(
+                "Waited {waited:?} for {kind} lock on `{}`, lock is contended",
+                self.lock_path
+            );
+        }
+        result
+    }
+
     fn file(&self) -> Result<File, LockerError> {
         File::open(&self.lock_path).map_err(|e| LockerError::Open(self.lock_path.clone(), e))
     }
 }
 
-#[tokio::main]
-async fn with_cancellation<F>(
-    lock: F,
+enum WaitError {
+    Lock(io::Error),
+    Cancelled,
+    Timeout(Duration),
+}
+
+// Blocking lock calls cannot be interrupted, so we poll instead. This way, the file is never held
+// beyond a timeout or a cancellation.
+fn wait_for_lock(
+    mut try_lock: impl FnMut() -> io::Result<()>,
     cancellation_token: &CancellationToken,
-) -> Result<File, LockerError>
-where
-    F: FnOnce() -> Result<File, LockerError> + Send + 'static,
-{
-    tokio::select! {
-        file = spawn_blocking(lock) => { file? }
-        _ = cancellation_token.cancelled() => { Err(LockerError::Cancelled) }
+    timeout: Option<Duration>,
+) -> Result<(), WaitError> {
+    let start = Instant::now();
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        match try_lock() {
+            Ok(()) => return Ok(()),
+            Err(error) if error.raw_os_error() != fs4::lock_contended_error().raw_os_error() => {
+                return Err(WaitError::Lock(error))
+            }
+            Err(_) => {}
+        }
+        if cancellation_token.is_cancelled() {
+            return Err(WaitError::Cancelled);
+        }
+        let remaining = match timeout {
+            Some(timeout) => match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => return Err(WaitError::Timeout(timeout)),
+            },
+            None => MAX_RETRY_DELAY,
+        };
+        sleep(delay.min(remaining));
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+// For sections which are rewritten regularly anyway, it is better to skip a write than to stop the
+// scheduler because some other process holds the lock for too long.
+pub fn tolerate_timeout(result: Result<(), Terminate>) -> Result<(), Terminate> {
+    match result {
+        Err(Terminate::Unrecoverable(error))
+            if matches!(
+                error.downcast_ref::<LockerError>(),
+                Some(LockerError::Timeout(..))
+            ) =>
+        {
+            warn!("Skipped writing section: {error:?}");
+            Ok(())
+        }
+        result => result,
     }
 }
 
@@ -108,3 +208,76 @@ impl Lock {
         self.0.unlock().map_err(|e| LockerError::Release(self.1, e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn read_lock_times_out() {
+        let lock_file = NamedTempFile::new().unwrap();
+        let lock_path = Utf8PathBuf::try_from(lock_file.path().to_path_buf()).unwrap();
+        let write_lock = Locker::new(&lock_path, None).wait_for_write_lock().unwrap();
+        assert!(matches!(
+            Locker::new(&lock_path, None)
+                .with_timeout(Duration::from_millis(100))
+                .wait_for_read_lock(),
+            Err(LockerError::Timeout(..))
+        ));
+        write_lock.release().unwrap();
+        Locker::new(&lock_path, None)
+            .with_timeout(Duration::from_millis(100))
+            .wait_for_read_lock()
+            .unwrap()
+            .release()
+            .unwrap();
+    }
+
+    #[test]
+    fn only_timeouts_are_tolerated() {
+        let timeout = LockerError::Timeout(Utf8PathBuf::from("lock"), Duration::from_secs(1));
+        assert!(tolerate_timeout(Err(timeout.into())).is_ok());
+        assert!(matches!(
+            tolerate_timeout(Err(LockerError::Cancelled.into())),
+            Err(Terminate::Cancelled)
+        ));
+        assert!(matches!(
+            tolerate_timeout(Err(Terminate::Unrecoverable(anyhow::anyhow!("failed")))),
+            Err(Terminate::Unrecoverable(..))
+        ));
+    }
+
+    #[test]
+    fn write_lock_is_cancelled() {
+        let lock_file = NamedTempFile::new().unwrap();
+        let lock_path = Utf8PathBuf::try_from(lock_file.path().to_path_buf()).unwrap();
+        let read_lock = Locker::new(&lock_path, None).wait_for_read_lock().unwrap();
+        let cancellation_token = CancellationToken::new();
+        cancellation_token.cancel();
+        assert!(matches!(
+            Locker::new(&lock_path, Some(&cancellation_token)).wait_for_write_lock(),
+            Err(LockerError::Cancelled)
+        ));
+        read_lock.release().unwrap();
+    }
+
+    #[test]
+    fn wait_statistics_are_shared_between_clones() {
+        let lock_file = NamedTempFile::new().unwrap();
+        let locker = Locker::new(
+            Utf8PathBuf::try_from(lock_file.path().to_path_buf()).unwrap(),
+            None,
+        );
+        locker.wait_for_read_lock().unwrap().release().unwrap();
+        locker
+            .clone()
+            .wait_for_write_lock()
+            .unwrap()
+            .release()
+            .unwrap();
+        let wait_statistics = locker.wait_statistics();
+        assert_eq!(wait_statistics.n_waits, 2);
+        assert_eq!(wait_statistics.n_contended_waits, 0);
+    }
+}
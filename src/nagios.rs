@@ -0,0 +1,340 @@
+use crate::config::Config;
+use crate::results::{AttemptOutcome, PlanExecutionReport, RebotOutcome};
+use crate::rf::output::test_durations;
+use crate::staleness::plan_staleness_threshold;
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckState {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl CheckState {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::Warning => 1,
+            Self::Critical => 2,
+            Self::Unknown => 3,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warning => "WARNING",
+            Self::Critical => "CRITICAL",
+            Self::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheckResult {
+    pub state: CheckState,
+    pub summary: String,
+    pub perfdata: Vec<String>,
+}
+
+impl CheckResult {
+    pub fn unknown(summary: impl Into<String>) -> Self {
+        Self {
+            state: CheckState::Unknown,
+            summary: summary.into(),
+            perfdata: vec![],
+        }
+    }
+}
+
+// Single status line as expected by Nagios and compatible monitoring systems.
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - {}", self.state.label(), self.summary)?;
+        if !self.perfdata.is_empty() {
+            write!(f, " | {}", self.perfdata.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// The state is derived from the outcome of the final attempt. Outcomes which do not tell us
+// anything about the tests themselves (e.g. a broken environment) result in UNKNOWN. Stale results
+// are always CRITICAL, since they indicate that the plan is not executed anymore.
+pub fn check_plan(
+    config: &Config,
+    plan_id: &str,
+    report: Option<&PlanExecutionReport>,
+    now: i64,
+) -> CheckResult {
+    let Some((execution_interval, plan_config)) = config
+        .plan_groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .plans
+                .iter()
+                .map(|plan| (group.execution_interval, plan))
+        })
+        .find(|(_, plan)| plan.id == plan_id)
+    else {
+        return CheckResult::unknown(format!("Plan {plan_id} is not configured"));
+    };
+    let Some(report) = report else {
+        return CheckResult::unknown(format!("Plan {plan_id}: no results available yet"));
+    };
+    let Some(final_attempt) = report.attempts.last() else {
+        return CheckResult::unknown(format!("Plan {plan_id}: no attempts were made"));
+    };
+
+    let n_attempts = report.attempts.len();
+    let (mut state, mut summary) = match &final_attempt.outcome {
+        AttemptOutcome::AllTestsPassed if n_attempts == 1 => {
+            (CheckState::Ok, "all tests passed".to_string())
+        }
+        AttemptOutcome::AllTestsPassed => (
+            CheckState::Warning,
+            format!("all tests passed after {n_attempts} attempts"),
+        ),
+        AttemptOutcome::TestFailures => (CheckState::Critical, "tests failed".into()),
+        AttemptOutcome::TimedOut => (CheckState::Critical, "timed out".into()),
+        AttemptOutcome::ResourceLimitExceeded(limit) => (
+            CheckState::Critical,
+            format!("resource limit exceeded ({limit:?})"),
+        ),
+        AttemptOutcome::RobotFailure => (CheckState::Unknown, "robot failure".into()),
+        AttemptOutcome::EnvironmentFailure => (CheckState::Unknown, "environment failure".into()),
+        AttemptOutcome::OtherError(error) => (CheckState::Unknown, format!("error: {error}")),
+    };
+    let age = now - report.timestamp;
+    let threshold = plan_staleness_threshold(execution_interval, &plan_config.execution_config);
+    if age > threshold {
+        state = CheckState::Critical;
+        summary = format!("{summary}, results are stale (age {age}s, threshold {threshold}s)");
+    }
+
+    let mut perfdata = vec![
+        format!(
+            "attempts={n_attempts};;;0;{}",
+            plan_config.execution_config.n_attempts_max
+        ),
+        format!(
+            "runtime={}s",
+            report
+                .attempts
+                .iter()
+                .map(|attempt| attempt.runtime)
+                .sum::<i64>()
+        ),
+    ];
+    if let Some(RebotOutcome::Ok(rebot_result)) = &report.rebot {
+        for (test, duration) in test_durations(&rebot_result.xml).unwrap_or_default() {
+            perfdata.push(format!("'{}'={duration}s", test.replace('\'', "''")));
+        }
+    }
+
+    CheckResult {
+        state,
+        summary: format!("Plan {plan_id}: {summary}"),
+        perfdata,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        EnvironmentConfig, ExecutionConfig, PlanConfig, PlanMetadata, RCCConfig, RCCProfileConfig,
+        RetryStrategy, RobotConfig, SequentialPlanGroup, SessionConfig, Source,
+        WorkingDirectoryCleanupConfig,
+    };
+    use crate::results::{AttemptReport, AttemptsConfig, Provenance, RebotResult, SourceKind};
+    use crate::section::Host;
+
+    const REBOT_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<robot generator="Rebot 7.0 (Python 3.11.6 on linux)" generated="2024-01-10T12:00:00.000000" rpa="false" schemaversion="5">
+<suite id="s1" name="Tasks" source="/tmp/tasks.robot">
+<test id="s1-t1" name="Opens Page" line="4">
+<status status="PASS" start="2024-01-10T12:00:00.000000" elapsed="1.5"/>
+</test>
+<status status="PASS" start="2024-01-10T12:00:00.000000" elapsed="1.5"/>
+</suite>
+</robot>
+"#;
+
+    fn config() -> Config {
+        Config {
+            working_directory: "/working".into(),
+            results_directory: "/results".into(),
+            managed_directory: "/managed".into(),
+            managed_robots_config: None,
+            environment_build_parallelism: None,
+            results_directory_lock_timeout: None,
+            rcc_config: RCCConfig {
+                binary_path: "/bin/rcc".into(),
+                profile_config: RCCProfileConfig::Default,
+            },
+            plan_groups: vec![SequentialPlanGroup {
+                plans: vec![PlanConfig {
+                    id: "plan".into(),
+                    source: Source::Manual {
+                        base_dir: "/suites".into(),
+                    },
+                    robot_config: RobotConfig {
+                        robot_target: "tasks.robot".into(),
+                        top_level_suite_name: None,
+                        suites: vec![],
+                        tests: vec![],
+                        test_tags_include: vec![],
+                        test_tags_exclude: vec![],
+                        variables: vec![],
+                        variable_files: vec![],
+                        argument_files: vec![],
+                        exit_on_failure: false,
+                    },
+                    execution_config: ExecutionConfig {
+                        n_attempts_max: 2,
+                        retry_strategy: RetryStrategy::Complete,
+                        timeout: 60,
+                        resource_limits_config: None,
+                        termination_config: None,
+                        orphan_process_policy: None,
+                    },
+                    environment_config: EnvironmentConfig::System,
+                    session_config: SessionConfig::Current,
+                    working_directory_cleanup_config: WorkingDirectoryCleanupConfig::MaxExecutions(
+                        5,
+                    ),
+                    host: Host::Source,
+                    section_config: None,
+                    metadata: PlanMetadata {
+                        application: "app".into(),
+                        suite_name: "suite".into(),
+                        variant: "".into(),
+                    },
+                }],
+                execution_interval: 300,
+            }],
+        }
+    }
+
+    fn attempt(index: usize, outcome: AttemptOutcome) -> AttemptReport {
+        AttemptReport {
+            index,
+            outcome,
+            runtime: 10,
+            resource_usage: None,
+            terminated_by: None,
+            timed_out_tests: vec![],
+            orphan_processes: vec![],
+        }
+    }
+
+    fn report(attempts: Vec<AttemptReport>) -> PlanExecutionReport {
+        PlanExecutionReport {
+            plan_id: "plan".into(),
+            timestamp: 1000,
+            attempts,
+            rebot: Some(RebotOutcome::Ok(RebotResult {
+                xml: REBOT_XML.into(),
+                html_base64: "".into(),
+                timestamp: 1000,
+                html_dropped: false,
+                xml_dropped: false,
+            })),
+            config: AttemptsConfig {
+                interval: 300,
+                timeout: 60,
+                n_attempts_max: 2,
+            },
+            metadata: PlanMetadata {
+                application: "app".into(),
+                suite_name: "suite".into(),
+                variant: "".into(),
+            },
+            provenance: Provenance {
+                source_kind: SourceKind::Manual,
+                source_version: None,
+                source_hash: None,
+                environment_hash: None,
+                holotree_space: None,
+            },
+            environment_build_resource_usage: None,
+            size_budget_exceeded: false,
+        }
+    }
+
+    #[test]
+    fn passed_in_first_attempt() {
+        let result = check_plan(
+            &config(),
+            "plan",
+            Some(&report(vec![attempt(1, AttemptOutcome::AllTestsPassed)])),
+            1100,
+        );
+        assert_eq!(result.state.exit_code(), 0);
+        assert_eq!(
+            result.to_string(),
+            "OK - Plan plan: all tests passed | attempts=1;;;0;2 runtime=10s 'Tasks.Opens Page'=1.5s"
+        );
+    }
+
+    #[test]
+    fn passed_after_retry() {
+        let result = check_plan(
+            &config(),
+            "plan",
+            Some(&report(vec![
+                attempt(1, AttemptOutcome::TestFailures),
+                attempt(2, AttemptOutcome::AllTestsPassed),
+            ])),
+            1100,
+        );
+        assert_eq!(result.state, CheckState::Warning);
+        assert_eq!(result.perfdata[1], "runtime=20s");
+    }
+
+    #[test]
+    fn environment_failure() {
+        let result = check_plan(
+            &config(),
+            "plan",
+            Some(&report(vec![attempt(
+                1,
+                AttemptOutcome::EnvironmentFailure,
+            )])),
+            1100,
+        );
+        assert_eq!(result.state, CheckState::Unknown);
+    }
+
+    #[test]
+    fn stale_results() {
+        let result = check_plan(
+            &config(),
+            "plan",
+            Some(&report(vec![attempt(1, AttemptOutcome::AllTestsPassed)])),
+            2000,
+        );
+        assert_eq!(result.state, CheckState::Critical);
+        assert_eq!(
+            result.summary,
+            "Plan plan: all tests passed, results are stale (age 1000s, threshold 540s)"
+        );
+    }
+
+    #[test]
+    fn missing_results() {
+        assert_eq!(
+            check_plan(&config(), "plan", None, 1100),
+            CheckResult::unknown("Plan plan: no results available yet")
+        );
+        assert_eq!(
+            check_plan(&config(), "other", None, 1100),
+            CheckResult::unknown("Plan other is not configured")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::OrphanProcessPolicy;
+use crate::results::OrphanProcess;
+
+use log::error;
+use sysinfo::{ProcessRefreshKind, System, UpdateKind};
+
+// Set for every child process we spawn. It is inherited by all descendants, including those which
+// leave the process group (e.g. browsers or web drivers started by a robot), such that we can find
+// them after the run.
+pub const RUN_ID_ENV_VAR: &str = "ROBOTMK_RUN_ID";
+
+pub fn handle_orphans(run_id: &str, policy: &OrphanProcessPolicy) -> Vec<OrphanProcess> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessRefreshKind::new().with_environ(UpdateKind::OnlyIfNotSet),
+    );
+    let marker = format!("{RUN_ID_ENV_VAR}={run_id}");
+    let mut orphans = vec![];
+    for (pid, process) in system.processes() {
+        if process.thread_kind().is_some() || !process.environ().contains(&marker) {
+            continue;
+        }
+        let killed = match policy {
+            OrphanProcessPolicy::Report => false,
+            OrphanProcessPolicy::Kill => process.kill(),
+        };
+        error!(
+            "Process {pid} ({}) outlived run {run_id}, killed: {killed}",
+            process.name()
+        );
+        orphans.push(OrphanProcess {
+            pid: pid.as_u32(),
+            name: process.name().to_string(),
+            killed,
+        });
+    }
+    orphans
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn spawn_marked_sleep(run_id: &str) -> std::process::Child {
+        Command::new("sleep")
+            .arg("30")
+            .env(RUN_ID_ENV_VAR, run_id)
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn report_orphans() {
+        let mut child = spawn_marked_sleep("robotmk_test_report_orphans");
+        let orphans = handle_orphans("robotmk_test_report_orphans", &OrphanProcessPolicy::Report);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(
+            orphans,
+            vec![OrphanProcess {
+                pid: child.id(),
+                name: "sleep".into(),
+                killed: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn kill_orphans() {
+        let mut child = spawn_marked_sleep("robotmk_test_kill_orphans");
+        let orphans = handle_orphans("robotmk_test_kill_orphans", &OrphanProcessPolicy::Kill);
+        assert!(!child.wait().unwrap().success());
+        assert_eq!(
+            orphans,
+            vec![OrphanProcess {
+                pid: child.id(),
+                name: "sleep".into(),
+                killed: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn no_orphans() {
+        assert!(handle_orphans("robotmk_test_no_orphans", &OrphanProcessPolicy::Kill).is_empty());
+    }
+
+    #[test]
+    fn processes_of_other_runs_are_ignored() {
+        let mut child = spawn_marked_sleep("robotmk_test_other_runs_1");
+        let orphans = handle_orphans("robotmk_test_other_runs_10", &OrphanProcessPolicy::Report);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(orphans.is_empty());
+    }
+}
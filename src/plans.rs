@@ -1,5 +1,8 @@
+use crate::config::{OrphanProcessPolicy, ResourceLimitsConfig, TerminationConfig};
 use crate::environment::{Environment, ResultCode};
+use crate::orphans::handle_orphans;
 use crate::results::{AttemptOutcome, AttemptReport, RebotOutcome};
+use crate::rf::output;
 use crate::rf::rebot::Rebot;
 use crate::rf::robot::{Attempt, Robot};
 use crate::session::{RunSpec, Session};
@@ -10,38 +13,30 @@ use chrono::Utc;
 use log::{error, info};
 use tokio_util::sync::CancellationToken;
 
+pub struct AttemptsSpec<'a> {
+    pub id: &'a str,
+    pub robot: &'a Robot,
+    pub environment: &'a Environment,
+    pub session: &'a Session,
+    pub timeout: u64,
+    pub resource_limits_config: Option<&'a ResourceLimitsConfig>,
+    pub termination_config: Option<&'a TerminationConfig>,
+    pub orphan_process_policy: Option<&'a OrphanProcessPolicy>,
+    pub cancellation_token: &'a CancellationToken,
+    pub output_directory: &'a Utf8Path,
+}
+
 pub fn run_attempts_with_rebot(
-    robot: &Robot,
-    id: &str,
-    environment: &Environment,
-    session: &Session,
-    timeout: u64,
-    cancellation_token: &CancellationToken,
-    output_directory: &Utf8Path,
+    spec: &AttemptsSpec,
 ) -> Result<(Vec<AttemptReport>, Option<RebotOutcome>), Cancelled> {
     let mut attempt_reports = vec![];
     let mut output_paths: Vec<Utf8PathBuf> = vec![];
 
-    for attempt in robot.attempts(output_directory) {
-        info!("Plan {id}: running attempt {}", attempt.index);
-        let attempt_index = attempt.index;
-        let starttime = Utc::now();
-        let (outcome, output_path) = run_attempt(
-            id,
-            environment,
-            session,
-            timeout,
-            attempt,
-            cancellation_token,
-            output_directory,
-        )?;
-        let endtime = Utc::now();
-        let success = matches!(&outcome, &AttemptOutcome::AllTestsPassed);
-        attempt_reports.push(AttemptReport {
-            index: attempt_index,
-            outcome,
-            runtime: (endtime - starttime).num_seconds(),
-        });
+    for attempt in spec.robot.attempts(spec.output_directory) {
+        info!("Plan {}: running attempt {}", spec.id, attempt.index);
+        let (attempt_report, output_path) = run_attempt(spec, attempt)?;
+        let success = matches!(&attempt_report.outcome, &AttemptOutcome::AllTestsPassed);
+        attempt_reports.push(attempt_report);
         if let Some(output_path) = output_path {
             output_paths.push(output_path);
         }
@@ -53,16 +48,16 @@ pub fn run_attempts_with_rebot(
     if output_paths.is_empty() {
         return Ok((attempt_reports, None));
     }
-    info!("Plan {id}: Running rebot");
+    info!("Plan {}: Running rebot", spec.id);
     let rebot = Rebot {
-        plan_id: id,
-        environment,
-        session,
-        base_path: output_directory.join("rebot"),
-        cancellation_token,
+        plan_id: spec.id,
+        environment: spec.environment,
+        session: spec.session,
+        base_path: spec.output_directory.join("rebot"),
+        cancellation_token: spec.cancellation_token,
         input_paths: &output_paths,
-        path_xml: &output_directory.join("rebot.xml"),
-        path_html: &output_directory.join("rebot.html"),
+        path_xml: &spec.output_directory.join("rebot.xml"),
+        path_html: &spec.output_directory.join("rebot.html"),
     }
     .rebot()?;
 
@@ -70,63 +65,184 @@ pub fn run_attempts_with_rebot(
 }
 
 fn run_attempt(
-    id: &str,
-    environment: &Environment,
-    session: &Session,
-    timeout: u64,
+    spec: &AttemptsSpec,
     attempt: Attempt,
-    cancellation_token: &CancellationToken,
-    output_directory: &Utf8Path,
-) -> Result<(AttemptOutcome, Option<Utf8PathBuf>), Cancelled> {
-    let log_message_start = format!("Plan {}, attempt {}", id, attempt.index);
+) -> Result<(AttemptReport, Option<Utf8PathBuf>), Cancelled> {
+    let log_message_start = format!("Plan {}, attempt {}", spec.id, attempt.index);
+    let starttime = Utc::now();
 
-    let run_outcome = match session
-        .run(&RunSpec {
-            id: &format!("robotmk_plan_{}_attempt_{}", id, attempt.index),
-            command_spec: &environment.wrap(attempt.command_spec),
-            base_path: &output_directory.join(attempt.index.to_string()),
-            timeout,
-            cancellation_token,
+    // Unique per execution, such that processes left over from earlier executions are not reported
+    // again.
+    let run_id = format!(
+        "robotmk_plan_{}_attempt_{}_{}",
+        spec.id,
+        attempt.index,
+        starttime.timestamp()
+    );
+    let run_report = match spec
+        .session
+        .run_and_report(&RunSpec {
+            id: &run_id,
+            command_spec: &spec.environment.wrap(attempt.command_spec),
+            base_path: &spec.output_directory.join(attempt.index.to_string()),
+            timeout: spec.timeout,
+            resource_limits_config: spec.resource_limits_config,
+            termination_config: spec.termination_config,
+            cancellation_token: spec.cancellation_token,
         })
         .context("Plan execution failed")
     {
-        Ok(run_outcome) => run_outcome,
+        Ok(run_report) => run_report,
         Err(error_) => {
             error!("{log_message_start}: {error_:?}");
-            return Ok((AttemptOutcome::OtherError(format!("{error_:?}")), None));
+            return Ok((
+                AttemptReport {
+                    index: attempt.index,
+                    outcome: AttemptOutcome::OtherError(format!("{error_:?}")),
+                    runtime: (Utc::now() - starttime).num_seconds(),
+                    resource_usage: None,
+                    terminated_by: None,
+                    timed_out_tests: vec![],
+                    orphan_processes: vec![],
+                },
+                None,
+            ));
         }
     };
-    let exit_code = match run_outcome {
-        Outcome::Completed(exit_code) => exit_code,
+    let runtime = (Utc::now() - starttime).num_seconds();
+    let orphan_processes = match spec.orphan_process_policy {
+        Some(orphan_process_policy) => handle_orphans(&run_id, orphan_process_policy),
+        None => vec![],
+    };
+    let mut timed_out_tests = vec![];
+    let (outcome, output_path) = match run_report.outcome {
+        Outcome::Completed(exit_code) => {
+            let (outcome, output_path) = evaluate_exit_code(
+                &log_message_start,
+                spec.environment,
+                exit_code,
+                attempt.output_xml_file,
+            );
+            match run_report.resource_limit_violation {
+                Some(resource_limit) if outcome != AttemptOutcome::AllTestsPassed => {
+                    error!("{log_message_start}: resource limit exceeded: {resource_limit:?}");
+                    (
+                        AttemptOutcome::ResourceLimitExceeded(resource_limit),
+                        output_path,
+                    )
+                }
+                _ => (outcome, output_path),
+            }
+        }
         Outcome::Timeout => {
             error!("{log_message_start}: robot run timed out");
-            return Ok((AttemptOutcome::TimedOut, None));
+            match salvage_partial_output(&log_message_start, attempt.output_xml_file) {
+                Some((output_path, tests)) => {
+                    timed_out_tests = tests;
+                    (AttemptOutcome::TimedOut, Some(output_path))
+                }
+                None => (AttemptOutcome::TimedOut, None),
+            }
         }
         Outcome::Cancel => {
             error!("{log_message_start}: robot run was cancelled");
             return Err(Cancelled {});
         }
     };
+    Ok((
+        AttemptReport {
+            index: attempt.index,
+            outcome,
+            runtime,
+            resource_usage: run_report.resource_usage,
+            terminated_by: run_report.terminated_by,
+            timed_out_tests,
+            orphan_processes,
+        },
+        output_path,
+    ))
+}
+
+// Robot Framework writes a valid output.xml if it manages to shut down gracefully after being
+// interrupted, such that the results of the tests which ran before the timeout are not lost.
+fn salvage_partial_output(
+    log_message_start: &str,
+    output_xml_file: Utf8PathBuf,
+) -> Option<(Utf8PathBuf, Vec<String>)> {
+    if !output_xml_file.exists() {
+        return None;
+    }
+    match output::timed_out_tests(&output_xml_file) {
+        Ok(timed_out_tests) => {
+            info!("{log_message_start}: salvaged partial output");
+            if !timed_out_tests.is_empty() {
+                if let Err(error) = output::tag_timed_out_tests(&output_xml_file, &timed_out_tests)
+                {
+                    error!("{log_message_start}: {error:?}");
+                }
+            }
+            Some((output_xml_file, timed_out_tests))
+        }
+        Err(error) => {
+            error!("{log_message_start}: partial output not usable: {error:?}");
+            None
+        }
+    }
+}
+
+fn evaluate_exit_code(
+    log_message_start: &str,
+    environment: &Environment,
+    exit_code: i32,
+    output_xml_file: Utf8PathBuf,
+) -> (AttemptOutcome, Option<Utf8PathBuf>) {
     match environment.create_result_code(exit_code) {
         ResultCode::AllTestsPassed => {
             info!("{log_message_start}: all tests passed");
-            Ok((
-                AttemptOutcome::AllTestsPassed,
-                Some(attempt.output_xml_file),
-            ))
+            (AttemptOutcome::AllTestsPassed, Some(output_xml_file))
         }
         ResultCode::EnvironmentFailed => {
             error!("{log_message_start}: environment failure");
-            Ok((AttemptOutcome::EnvironmentFailure, None))
+            (AttemptOutcome::EnvironmentFailure, None)
         }
         ResultCode::RobotCommandFailed => {
-            if attempt.output_xml_file.exists() {
+            if output_xml_file.exists() {
                 info!("{log_message_start}: some tests failed");
-                Ok((AttemptOutcome::TestFailures, Some(attempt.output_xml_file)))
+                (AttemptOutcome::TestFailures, Some(output_xml_file))
             } else {
                 error!("{log_message_start}: robot failure (no output)");
-                Ok((AttemptOutcome::RobotFailure, None))
+                (AttemptOutcome::RobotFailure, None)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{copy, read_to_string, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn salvage_partial_output_of_fixture() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let output_xml_file =
+            Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?.join("output.xml");
+        assert!(salvage_partial_output("test", output_xml_file.clone()).is_none());
+
+        copy("tests/timeout/partial_output.xml", &output_xml_file)?;
+        assert_eq!(
+            salvage_partial_output("test", output_xml_file.clone()),
+            Some((
+                output_xml_file.clone(),
+                vec![String::from("Tasks.Spawn Child")]
+            ))
+        );
+        let tagged = read_to_string(&output_xml_file)?;
+        assert_eq!(tagged.matches(output::TIMED_OUT_TAG).count(), 1);
+
+        write(&output_xml_file, &tagged[..tagged.len() / 2])?;
+        assert!(salvage_partial_output("test", output_xml_file).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use crate::results::ResourceUsage;
+
+use std::cmp::max;
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+use sysinfo::{Pid, System};
+use tokio::time::{interval, Instant};
+
+const SAMPLING_INTERVAL: Duration = Duration::from_secs(1);
+
+// sysinfo offers no accumulated CPU time (at least not in the version we use), so we integrate the
+// CPU usage over the sampling intervals. sysinfo reports the CPU usage of a process only from its
+// second sample onwards, so short-lived processes are not (or only partially) accounted for.
+pub struct ResourceMonitor {
+    system: System,
+    top_pid: Option<Pid>,
+    usage: ResourceUsage,
+    last_sample: Option<Instant>,
+}
+
+impl ResourceMonitor {
+    pub fn new(top_pid: Option<Pid>) -> Self {
+        Self {
+            system: System::new(),
+            top_pid,
+            usage: ResourceUsage::default(),
+            last_sample: None,
+        }
+    }
+
+    pub async fn monitor<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        tokio::pin!(future);
+        let mut clock = interval(SAMPLING_INTERVAL);
+        loop {
+            tokio::select! {
+                output = &mut future => { return output },
+                _ = clock.tick() => { self.sample() },
+            }
+        }
+    }
+
+    pub fn sample(&mut self) {
+        let Some(top_pid) = self.top_pid else {
+            return;
+        };
+        self.system.refresh_processes();
+        let now = Instant::now();
+        let mut memory = 0;
+        let mut cpu_usage = 0.0;
+        let pids_in_tree = process_tree(&top_pid, &self.system);
+        for pid in pids_in_tree.iter() {
+            if let Some(process) = self.system.process(*pid) {
+                memory += process.memory();
+                cpu_usage += process.cpu_usage() as f64;
+            }
+        }
+        self.usage.peak_memory = max(self.usage.peak_memory, memory);
+        self.usage.peak_process_count = max(self.usage.peak_process_count, pids_in_tree.len());
+        if let Some(last_sample) = self.last_sample {
+            self.usage.cpu_time += cpu_usage / 100.0 * (now - last_sample).as_secs_f64();
+        }
+        self.last_sample = Some(now);
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        self.usage.clone()
+    }
+}
+
+pub fn process_tree(top_pid: &Pid, system: &System) -> HashSet<Pid> {
+    let mut pids_in_tree = HashSet::new();
+    if system.process(*top_pid).is_none() {
+        return pids_in_tree;
+    }
+    pids_in_tree.insert(*top_pid);
+    loop {
+        let current_tree_size = pids_in_tree.len();
+        for (pid, process) in system.processes() {
+            if process.thread_kind().is_some() {
+                continue;
+            }
+            if let Some(parent_pid) = process.parent() {
+                if pids_in_tree.contains(&parent_pid) {
+                    pids_in_tree.insert(*pid);
+                }
+            }
+        }
+        if pids_in_tree.len() == current_tree_size {
+            break;
+        }
+    }
+    pids_in_tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sysinfo::get_current_pid;
+
+    #[test]
+    fn process_tree_contains_top_process() {
+        let mut system = System::new();
+        system.refresh_processes();
+        let current_pid = get_current_pid().unwrap();
+        assert!(process_tree(&current_pid, &system).contains(&current_pid));
+    }
+
+    #[test]
+    fn process_tree_of_vanished_process_is_empty() {
+        let mut system = System::new();
+        system.refresh_processes();
+        assert!(process_tree(&Pid::from_u32(u32::MAX), &system).is_empty());
+    }
+
+    #[test]
+    fn sample_without_process() {
+        let mut resource_monitor = ResourceMonitor::new(None);
+        resource_monitor.sample();
+        assert_eq!(resource_monitor.usage(), ResourceUsage::default());
+    }
+
+    #[test]
+    fn sample_current_process() {
+        let mut resource_monitor = ResourceMonitor::new(Some(get_current_pid().unwrap()));
+        resource_monitor.sample();
+        let usage = resource_monitor.usage();
+        assert!(usage.peak_memory > 0);
+        assert!(usage.peak_process_count >= 1);
+    }
+}
@@ -1,13 +1,31 @@
-use crate::config::PlanMetadata;
-use crate::section::{WritePiggybackSection, WriteSection};
+use crate::config::{PlanMetadata, SectionCompression, TerminationSignal};
+use crate::section::{Host, Section, WritePiggybackSection, WriteSection};
 use camino::{Utf8Path, Utf8PathBuf};
-use serde::Serialize;
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+// Interval (in seconds) in which the scheduler renews its heartbeat.
+pub const SCHEDULER_HEARTBEAT_INTERVAL: u64 = 30;
+
 pub fn plan_results_directory(results_directory: &Utf8Path) -> Utf8PathBuf {
     results_directory.join("plans")
 }
 
+// Guards all reads and writes of the results directory. Older scheduler versions locked the
+// configuration file instead.
+pub fn results_directory_lock_path(results_directory: &Utf8Path) -> Utf8PathBuf {
+    results_directory.join("results.lock")
+}
+
+pub fn scheduler_heartbeat_path(results_directory: &Utf8Path) -> Utf8PathBuf {
+    results_directory.join("scheduler_heartbeat.json")
+}
+
+pub fn scheduler_health_path(results_directory: &Utf8Path) -> Utf8PathBuf {
+    results_directory.join("scheduler_health.json")
+}
+
 #[derive(Serialize)]
 pub enum SchedulerPhase {
     ManagedRobots,
@@ -23,6 +41,45 @@ impl WriteSection for SchedulerPhase {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct SchedulerHeartbeat {
+    pub timestamp: i64,
+}
+
+impl WriteSection for SchedulerHeartbeat {
+    fn name() -> &'static str {
+        "robotmk_scheduler_heartbeat"
+    }
+}
+
+#[derive(Serialize)]
+pub struct SchedulerHealth {
+    pub timestamp: i64,
+    pub pid: u32,
+    pub start_time: i64,
+    pub uptime: i64,
+    pub version: String,
+    pub config_hash: String,
+    pub active_plan_group_loops: usize,
+    pub last_plan_completion: Option<i64>,
+    pub lock_contention: LockContention,
+}
+
+// Waits for the results directory lock. Contended waits indicate that some other process holds
+// the lock for too long.
+#[derive(Serialize)]
+pub struct LockContention {
+    pub n_waits: usize,
+    pub n_contended_waits: usize,
+    pub longest_wait: f64,
+}
+
+impl WriteSection for SchedulerHealth {
+    fn name() -> &'static str {
+        "robotmk_scheduler_health"
+    }
+}
+
 #[derive(Serialize)]
 pub struct SetupFailures(pub Vec<SetupFailure>);
 
@@ -39,6 +96,24 @@ pub struct SetupFailure {
     pub details: String,
 }
 
+// Measured after unpacking, sizes are in bytes.
+#[derive(Serialize)]
+pub struct ManagedRobots(pub Vec<ManagedRobot>);
+
+impl WriteSection for ManagedRobots {
+    fn name() -> &'static str {
+        "robotmk_managed_robots"
+    }
+}
+
+#[derive(Serialize)]
+pub struct ManagedRobot {
+    pub plan_id: String,
+    pub sha256: String,
+    pub unpacked_size: u64,
+    pub size_limit: u64,
+}
+
 #[derive(Serialize)]
 pub struct BuildStates<'a>(pub &'a HashMap<String, EnvironmentBuildStage>);
 
@@ -50,12 +125,19 @@ impl WriteSection for BuildStates<'_> {
 
 #[derive(PartialEq, Debug, Serialize, Clone)]
 pub enum BuildOutcome {
-    NotNeeded,
+    NotNeeded(NotNeededReason),
     Success(i64),
     Timeout,
     Error(String),
 }
 
+#[derive(PartialEq, Debug, Serialize, Clone)]
+pub enum NotNeededReason {
+    NoBuildRequired,
+    // An identical environment was built successfully before
+    CacheHit,
+}
+
 #[derive(Serialize)]
 pub enum EnvironmentBuildStage {
     Pending,
@@ -63,7 +145,7 @@ pub enum EnvironmentBuildStage {
     Complete(BuildOutcome),
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct PlanExecutionReport {
     pub plan_id: String,
     pub timestamp: i64,
@@ -71,53 +153,304 @@ pub struct PlanExecutionReport {
     pub rebot: Option<RebotOutcome>,
     pub config: AttemptsConfig,
     pub metadata: PlanMetadata,
+    pub provenance: Provenance,
+    pub environment_build_resource_usage: Option<ResourceUsage>,
+    // Set if the section exceeds the size budget even without the rebot logs
+    pub size_budget_exceeded: bool,
+}
+
+// Identifies what a plan run used. The source version is the version resolved from an artifact
+// repository or the commit checked out from Git. The source hash is the digest of the archive for
+// managed sources and a hash of the directory content otherwise. For RCC plans, the environment
+// hash covers `robot.yaml` and `conda.yaml`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Provenance {
+    pub source_kind: SourceKind,
+    pub source_version: Option<String>,
+    pub source_hash: Option<String>,
+    pub environment_hash: Option<String>,
+    pub holotree_space: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SourceKind {
+    Manual,
+    Managed,
+    Repository,
+    Git,
 }
 
 impl WritePiggybackSection for PlanExecutionReport {
     fn name() -> &'static str {
         "robotmk_plan_execution_report"
     }
+
+    fn version(&self) -> Option<i64> {
+        Some(self.timestamp)
+    }
 }
 
-#[derive(PartialEq, Debug, Serialize)]
+impl PlanExecutionReport {
+    // Drops the logs of the rebot result, HTML first, until the section fits into the size budget.
+    pub fn section_within_budget(
+        &mut self,
+        host: Host,
+        compression: Option<&SectionCompression>,
+        size_budget: usize,
+    ) -> Section {
+        loop {
+            let section = self.section(host.clone(), compression);
+            if section.content.len() <= size_budget {
+                return section;
+            }
+            match self.drop_log() {
+                Some(log) => warn!(
+                    "Plan {}: Results exceed size budget of {size_budget} bytes, dropping {log} log",
+                    self.plan_id
+                ),
+                None => {
+                    warn!(
+                        "Plan {}: Results exceed size budget of {size_budget} bytes even without \
+                         rebot logs",
+                        self.plan_id
+                    );
+                    self.size_budget_exceeded = true;
+                    return self.section(host, compression);
+                }
+            }
+        }
+    }
+
+    // Returns the kind of log which was dropped, `None` if there was nothing left to drop.
+    fn drop_log(&mut self) -> Option<&'static str> {
+        let Some(RebotOutcome::Ok(rebot_result)) = &mut self.rebot else {
+            return None;
+        };
+        if !rebot_result.html_dropped {
+            rebot_result.html_base64 = String::new();
+            rebot_result.html_dropped = true;
+            return Some("HTML");
+        }
+        if !rebot_result.xml_dropped {
+            rebot_result.xml = String::new();
+            rebot_result.xml_dropped = true;
+            return Some("XML");
+        }
+        None
+    }
+}
+
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 pub struct AttemptReport {
     pub index: usize,
     pub outcome: AttemptOutcome,
     pub runtime: i64,
+    pub resource_usage: Option<ResourceUsage>,
+    pub terminated_by: Option<TerminatedBy>,
+    pub timed_out_tests: Vec<String>,
+    pub orphan_processes: Vec<OrphanProcess>,
 }
 
-#[derive(PartialEq, Debug, Serialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ResourceUsage {
+    pub peak_memory: u64,
+    pub cpu_time: f64,
+    pub peak_process_count: usize,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 pub enum AttemptOutcome {
     AllTestsPassed,
     TestFailures,
     RobotFailure,
     EnvironmentFailure,
     TimedOut,
+    ResourceLimitExceeded(ResourceLimit),
     OtherError(String),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
+pub enum ResourceLimit {
+    Memory,
+    Pids,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
+pub struct OrphanProcess {
+    pub pid: u32,
+    pub name: String,
+    pub killed: bool,
+}
+
+// Records how a timed out or cancelled robot run was brought down, either by one of the configured
+// escalation stages or by killing the remaining process tree.
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
+pub enum TerminatedBy {
+    Stage {
+        index: usize,
+        signal: TerminationSignal,
+    },
+    ProcessTreeKill,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum RebotOutcome {
     Ok(RebotResult),
     Error(String),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RebotResult {
     pub xml: String,
     pub html_base64: String,
     pub timestamp: i64,
+    pub html_dropped: bool,
+    pub xml_dropped: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct AttemptsConfig {
     pub interval: u64,
     pub timeout: u64,
     pub n_attempts_max: usize,
 }
 
+#[derive(PartialEq, Debug, Serialize)]
+pub struct StalenessReport {
+    pub scheduler_heartbeat_age: Option<i64>,
+    pub scheduler_stale: bool,
+    pub stale_plans: Vec<StalePlan>,
+}
+
+#[derive(PartialEq, Debug, Serialize)]
+pub struct StalePlan {
+    pub plan_id: String,
+    pub age: i64,
+    pub threshold: i64,
+}
+
+#[derive(Serialize)]
+pub enum ResultsReadingStatus {
+    Ok,
+    SchedulerNotStarted,
+    ResultsDirectoryMissing(Utf8PathBuf),
+    // E.g. the agent lacks the permissions to open the lock file.
+    LockFileInaccessible(String),
+    // The scheduler holds the lock for too long.
+    LockTimedOut(String),
+    LockAcquisitionFailed(String),
+}
+
+impl WriteSection for ResultsReadingStatus {
+    fn name() -> &'static str {
+        "robotmk_results_reading_status"
+    }
+}
+
+// Only emitted in incremental mode of the agent plugin. Unless the state is Ok, all sections are
+// delivered again on the next run.
+#[derive(Serialize)]
+pub enum DeliveryStateStatus {
+    Ok,
+    StateDirectoryUnknown,
+    WriteFailed(String),
+}
+
+impl WriteSection for DeliveryStateStatus {
+    fn name() -> &'static str {
+        "robotmk_delivery_state_status"
+    }
+}
+
+#[derive(Serialize)]
+pub struct SectionReadErrors(pub Vec<SectionReadError>);
+
+impl WriteSection for SectionReadErrors {
+    fn name() -> &'static str {
+        "robotmk_section_read_errors"
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SectionReadError {
+    pub path: Option<Utf8PathBuf>,
+    pub error: String,
+}
+
 #[derive(Serialize)]
 pub enum ConfigSection {
     ReadingError(String),
     FileContent(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> PlanExecutionReport {
+        PlanExecutionReport {
+            plan_id: "plan".into(),
+            timestamp: 1000,
+            attempts: vec![],
+            rebot: Some(RebotOutcome::Ok(RebotResult {
+                xml: "x".repeat(1000),
+                html_base64: "h".repeat(1000),
+                timestamp: 1000,
+                html_dropped: false,
+                xml_dropped: false,
+            })),
+            config: AttemptsConfig {
+                interval: 300,
+                timeout: 60,
+                n_attempts_max: 1,
+            },
+            metadata: PlanMetadata {
+                application: "app".into(),
+                suite_name: "suite".into(),
+                variant: "".into(),
+            },
+            provenance: Provenance {
+                source_kind: SourceKind::Manual,
+                source_version: None,
+                source_hash: None,
+                environment_hash: None,
+                holotree_space: None,
+            },
+            environment_build_resource_usage: None,
+            size_budget_exceeded: false,
+        }
+    }
+
+    fn dropped(report: &PlanExecutionReport) -> (bool, bool) {
+        match &report.rebot {
+            Some(RebotOutcome::Ok(rebot_result)) => {
+                (rebot_result.html_dropped, rebot_result.xml_dropped)
+            }
+            _ => panic!("Unexpected rebot outcome"),
+        }
+    }
+
+    #[test]
+    fn section_within_budget() {
+        let full_size = report().section(Host::Source, None).content.len();
+
+        let mut within_budget = report();
+        within_budget.section_within_budget(Host::Source, None, full_size);
+        assert_eq!(dropped(&within_budget), (false, false));
+
+        let mut without_html = report();
+        let section = without_html.section_within_budget(Host::Source, None, full_size - 1000);
+        assert!(section.content.len() <= full_size - 1000);
+        assert_eq!(dropped(&without_html), (true, false));
+        assert!(!without_html.size_budget_exceeded);
+    }
+
+    #[test]
+    fn section_exceeds_budget_without_logs() {
+        let mut report = report();
+        let section = report.section_within_budget(Host::Source, None, 10);
+        assert_eq!(dropped(&report), (true, true));
+        assert!(report.size_budget_exceeded);
+        assert!(section.content.contains(r#""size_budget_exceeded":true"#));
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Context, Result as AnyhowResult};
+use camino::Utf8Path;
+use chrono::NaiveDateTime;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::fs::{read_to_string, write};
+
+// Added to the tests which were terminated, such that they are recognizable in the merged report.
+pub const TIMED_OUT_TAG: &str = "robotmk:timed_out";
+
+// Messages with which Robot Framework fails the test running at the time it is stopped by a signal
+// and all tests which would have run afterwards.
+const TERMINATION_MESSAGES: [&str; 2] = [
+    "Execution terminated by signal",
+    "Test execution stopped due to a fatal error.",
+];
+
+// Parses an output.xml written by an interrupted robot run. An error means that the file is not
+// usable, for example because Robot Framework was killed while writing it. Otherwise, the full
+// names of the tests which were terminated are returned.
+pub fn timed_out_tests(output_xml: &Utf8Path) -> AnyhowResult<Vec<String>> {
+    parse_timed_out_tests(
+        &read_to_string(output_xml).context(format!("Failed to read {output_xml}"))?,
+    )
+    .context(format!("{output_xml} is not a complete output XML"))
+}
+
+pub fn tag_timed_out_tests(output_xml: &Utf8Path, timed_out_tests: &[String]) -> AnyhowResult<()> {
+    let tagged = add_timed_out_tag(
+        &read_to_string(output_xml).context(format!("Failed to read {output_xml}"))?,
+        timed_out_tests,
+    )
+    .context(format!("Failed to tag timed out tests in {output_xml}"))?;
+    write(output_xml, tagged).context(format!("Failed to write {output_xml}"))
+}
+
+// Robot Framework places the tags of a test in front of its status.
+fn add_timed_out_tag(output_xml: &str, timed_out_tests: &[String]) -> AnyhowResult<String> {
+    let mut reader = Reader::from_str(output_xml);
+    let mut writer = Writer::new(Vec::new());
+    let mut element_stack: Vec<Vec<u8>> = vec![];
+    let mut name_stack: Vec<String> = vec![];
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Start(element) | Event::Empty(element)
+                if element.name().as_ref() == b"status"
+                    && element_stack.ends_with(&[b"test".to_vec()])
+                    && timed_out_tests.contains(&name_stack.join(".")) =>
+            {
+                writer.write_event(Event::Start(BytesStart::new("tag")))?;
+                writer.write_event(Event::Text(BytesText::new(TIMED_OUT_TAG)))?;
+                writer.write_event(Event::End(BytesEnd::new("tag")))?;
+                writer.write_event(Event::Text(BytesText::new("\n")))?;
+            }
+            _ => {}
+        }
+        match &event {
+            Event::Start(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.push(name_attribute(element)?);
+                }
+                element_stack.push(tag);
+            }
+            Event::End(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.pop();
+                }
+                element_stack.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        writer.write_event(event)?;
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+fn parse_timed_out_tests(output_xml: &str) -> AnyhowResult<Vec<String>> {
+    let mut reader = Reader::from_str(output_xml);
+    let mut element_stack: Vec<Vec<u8>> = vec![];
+    let mut name_stack: Vec<String> = vec![];
+    let mut found_root = false;
+    let mut timed_out_tests = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if element_stack.is_empty() {
+                    if tag != b"robot" {
+                        bail!("Unexpected root element")
+                    }
+                    found_root = true;
+                }
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.push(name_attribute(&element)?);
+                }
+                element_stack.push(tag);
+            }
+            Event::End(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.pop();
+                }
+                element_stack.pop();
+            }
+            Event::Text(text)
+                if element_stack.ends_with(&[b"test".to_vec(), b"status".to_vec()]) =>
+            {
+                let message = text.unescape()?;
+                if TERMINATION_MESSAGES
+                    .iter()
+                    .any(|termination| message.contains(termination))
+                {
+                    timed_out_tests.push(name_stack.join("."));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !found_root || !element_stack.is_empty() {
+        bail!("Output XML is truncated")
+    }
+    Ok(timed_out_tests)
+}
+
+// Returns the full names and durations (in seconds) of all tests in an output XML. Robot Framework 7
+// records the elapsed time directly, older versions write start and end time stamps instead.
+pub fn test_durations(output_xml: &str) -> AnyhowResult<Vec<(String, f64)>> {
+    let mut reader = Reader::from_str(output_xml);
+    let mut element_stack: Vec<Vec<u8>> = vec![];
+    let mut name_stack: Vec<String> = vec![];
+    let mut durations = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if tag == b"status" && element_stack.ends_with(&[b"test".to_vec()]) {
+                    durations.push((name_stack.join("."), status_duration(&element)?));
+                }
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.push(name_attribute(&element)?);
+                }
+                element_stack.push(tag);
+            }
+            Event::Empty(element)
+                if element.name().as_ref() == b"status"
+                    && element_stack.ends_with(&[b"test".to_vec()]) =>
+            {
+                durations.push((name_stack.join("."), status_duration(&element)?));
+            }
+            Event::End(element) => {
+                let tag = element.name().as_ref().to_vec();
+                if is_named_item(&tag, &element_stack) {
+                    name_stack.pop();
+                }
+                element_stack.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(durations)
+}
+
+fn status_duration(element: &BytesStart) -> AnyhowResult<f64> {
+    if let Some(elapsed) = element.try_get_attribute("elapsed")? {
+        return elapsed
+            .unescape_value()?
+            .parse()
+            .context("Invalid elapsed time");
+    }
+    let timestamp = |name: &str| -> AnyhowResult<NaiveDateTime> {
+        let value = element
+            .try_get_attribute(name)?
+            .context(format!("Status has no {name}"))?
+            .unescape_value()?
+            .to_string();
+        NaiveDateTime::parse_from_str(&value, "%Y%m%d %H:%M:%S%.3f")
+            .context(format!("Invalid {name}: {value}"))
+    };
+    Ok((timestamp("endtime")? - timestamp("starttime")?).num_milliseconds() as f64 / 1000.0)
+}
+
+// The statistics section contains `suite` elements as well, which are not part of the result tree.
+fn is_named_item(tag: &[u8], element_stack: &[Vec<u8>]) -> bool {
+    (tag == b"suite" || tag == b"test") && !element_stack.iter().any(|tag| tag == b"statistics")
+}
+
+fn name_attribute(element: &BytesStart) -> AnyhowResult<String> {
+    Ok(element
+        .try_get_attribute("name")?
+        .context("Element has no name")?
+        .unescape_value()?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<robot generator="Robot 7.0 (Python 3.11.6 on linux)" generated="2024-01-10T12:00:00.000000" rpa="false" schemaversion="5">
+<suite id="s1" name="Tasks" source="/tmp/tasks.robot">
+<test id="s1-t1" name="Passes" line="4">
+<kw name="Log" owner="BuiltIn">
+<status status="PASS" start="2024-01-10T12:00:00.000000" elapsed="0.001"/>
+</kw>
+<status status="PASS" start="2024-01-10T12:00:00.000000" elapsed="0.001"/>
+</test>
+<test id="s1-t2" name="Sleeps" line="7">
+<kw name="Sleep" owner="BuiltIn">
+<status status="FAIL" start="2024-01-10T12:00:00.000000" elapsed="3.0">Execution terminated by signal</status>
+</kw>
+<status status="FAIL" start="2024-01-10T12:00:00.000000" elapsed="3.0">Execution terminated by signal</status>
+</test>
+<test id="s1-t3" name="Never Runs" line="10">
+<status status="FAIL" start="2024-01-10T12:00:03.000000" elapsed="0.0">Test execution stopped due to a fatal error.</status>
+</test>
+<status status="FAIL" start="2024-01-10T12:00:00.000000" elapsed="3.0"/>
+</suite>
+<statistics>
+<suite>
+<stat pass="1" fail="2" skip="0" id="s1" name="Tasks">Tasks</stat>
+</suite>
+</statistics>
+<errors>
+</errors>
+</robot>
+"#;
+
+    #[test]
+    fn timed_out_tests_of_complete_output() {
+        assert_eq!(
+            parse_timed_out_tests(OUTPUT_XML).unwrap(),
+            vec![
+                String::from("Tasks.Sleeps"),
+                String::from("Tasks.Never Runs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_durations_of_rf7_output() {
+        assert_eq!(
+            test_durations(OUTPUT_XML).unwrap(),
+            vec![
+                (String::from("Tasks.Passes"), 0.001),
+                (String::from("Tasks.Sleeps"), 3.0),
+                (String::from("Tasks.Never Runs"), 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_durations_of_rf6_output() {
+        let output_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<robot generator="Robot 6.1.1 (Python 3.11.6 on linux)" generated="20240110 12:00:00.000" rpa="false" schemaversion="4">
+<suite id="s1" name="Tasks" source="/tmp/tasks.robot">
+<test id="s1-t1" name="Passes" line="4">
+<kw name="Sleep" library="BuiltIn">
+<status status="PASS" starttime="20240110 12:00:00.000" endtime="20240110 12:00:01.500"/>
+</kw>
+<status status="PASS" starttime="20240110 12:00:00.000" endtime="20240110 12:00:01.500"/>
+</test>
+<status status="PASS" starttime="20240110 12:00:00.000" endtime="20240110 12:00:01.500"/>
+</suite>
+</robot>
+"#;
+        assert_eq!(
+            test_durations(output_xml).unwrap(),
+            vec![(String::from("Tasks.Passes"), 1.5)]
+        );
+    }
+
+    #[test]
+    fn tag_timed_out_test() {
+        let tagged = add_timed_out_tag(OUTPUT_XML, &[String::from("Tasks.Sleeps")]).unwrap();
+        assert_eq!(tagged.matches(TIMED_OUT_TAG).count(), 1);
+        assert!(tagged.contains(
+            r#"<tag>robotmk:timed_out</tag>
+<status status="FAIL" start="2024-01-10T12:00:00.000000" elapsed="3.0">Execution terminated by signal</status>
+</test>
+<test id="s1-t3" name="Never Runs" line="10">"#
+        ));
+        assert_eq!(
+            tagged.replace("<tag>robotmk:timed_out</tag>\n", ""),
+            OUTPUT_XML
+        );
+    }
+
+    #[test]
+    fn timed_out_tests_of_truncated_output() {
+        assert!(parse_timed_out_tests(&OUTPUT_XML[..OUTPUT_XML.len() / 2]).is_err());
+    }
+}
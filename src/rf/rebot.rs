@@ -77,6 +77,8 @@ impl Rebot<'_> {
             command_spec: &self.environment.wrap(self.build_rebot_command_spec()),
             base_path: &self.base_path,
             timeout: 120,
+            resource_limits_config: None,
+            termination_config: None,
             cancellation_token: self.cancellation_token,
         })
     }
@@ -104,6 +106,8 @@ impl Rebot<'_> {
                     xml: merged_xml,
                     html_base64: general_purpose::STANDARD.encode(merged_html),
                     timestamp,
+                    html_dropped: false,
+                    xml_dropped: false,
                 }),
                 Err(error) => {
                     let error_message = format!(
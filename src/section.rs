@@ -1,10 +1,17 @@
 use super::lock::{Locker, LockerError};
 
+use crate::config::SectionCompression;
+use crate::results::SectionReadError;
 use crate::termination::Terminate;
 use anyhow::{anyhow, Context, Result as AnyhowResult};
+use base64::{engine::general_purpose, Engine};
 use camino::{Utf8Path, Utf8PathBuf};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io::{Read, Write};
 use std::path::Path;
 use walkdir::{DirEntry, WalkDir};
 
@@ -19,27 +26,110 @@ pub struct Section {
     pub host: Host,
     pub name: String,
     pub content: String,
+    pub compression: Option<SectionCompression>,
+    pub version: Option<i64>,
+}
+
+impl Section {
+    // Compressed content is base64-encoded, such that the section stays valid UTF-8.
+    pub fn new(
+        name: &str,
+        content: &str,
+        host: Host,
+        compression: Option<&SectionCompression>,
+    ) -> Self {
+        Self {
+            host,
+            name: name.into(),
+            content: match compression {
+                Some(SectionCompression::Gzip) => general_purpose::STANDARD.encode(gzip(content)),
+                None => content.into(),
+            },
+            compression: compression.cloned(),
+            version: None,
+        }
+    }
+
+    pub fn write(&self, path: impl AsRef<Utf8Path>, locker: &Locker) -> Result<(), Terminate> {
+        write(self, path, locker)
+    }
+
+    pub fn decompressed_content(&self) -> AnyhowResult<String> {
+        match self.compression {
+            Some(SectionCompression::Gzip) => {
+                let mut decompressed = String::new();
+                GzDecoder::new(
+                    general_purpose::STANDARD
+                        .decode(&self.content)
+                        .context("Section content is not valid base64")?
+                        .as_slice(),
+                )
+                .read_to_string(&mut decompressed)
+                .context("Failed to decompress section content")?;
+                Ok(decompressed)
+            }
+            None => Ok(self.content.clone()),
+        }
+    }
+}
+
+fn gzip(content: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder
+        .write_all(content.as_bytes())
+        .expect("Unexpected error while compressing in memory");
+    encoder
+        .finish()
+        .expect("Unexpected error while compressing in memory")
 }
 
 pub trait WriteSection {
     fn name() -> &'static str;
 
+    fn section(&self) -> Section
+    where
+        Self: Serialize,
+    {
+        Section::new(
+            Self::name(),
+            &serde_json::to_string(&self).unwrap(),
+            Host::Source,
+            None,
+        )
+    }
+
     fn write(&self, path: impl AsRef<Utf8Path>, locker: &Locker) -> Result<(), Terminate>
     where
         Self: Serialize,
     {
-        let section = Section {
-            name: Self::name().into(),
-            content: serde_json::to_string(&self).unwrap(),
-            host: Host::Source,
-        };
-        write(&section, path, locker)
+        self.section().write(path, locker)
     }
 }
 
 pub trait WritePiggybackSection {
     fn name() -> &'static str;
 
+    // Sections with a version are only delivered once per version in incremental mode of the agent
+    // plugin. Sections without a version are always delivered.
+    fn version(&self) -> Option<i64> {
+        None
+    }
+
+    fn section(&self, host: Host, compression: Option<&SectionCompression>) -> Section
+    where
+        Self: Serialize,
+    {
+        Section {
+            version: self.version(),
+            ..Section::new(
+                Self::name(),
+                &serde_json::to_string(&self).unwrap(),
+                host,
+                compression,
+            )
+        }
+    }
+
     fn write(
         &self,
         path: impl AsRef<Utf8Path>,
@@ -49,25 +139,76 @@ pub trait WritePiggybackSection {
     where
         Self: Serialize,
     {
-        let section = Section {
-            name: Self::name().into(),
-            content: serde_json::to_string(&self).unwrap(),
-            host,
-        };
-        write(&section, path, locker)
+        self.section(host, None).write(path, locker)
     }
 }
 
-pub fn read(directory: impl AsRef<Path>, locker: &Locker) -> Result<Vec<Section>, LockerError> {
-    // TODO: Test this function.
+pub fn read_section(path: &Utf8Path, locker: &Locker) -> AnyhowResult<Option<Section>> {
     let lock = locker.wait_for_read_lock()?;
-    let sections = WalkDir::new(directory)
-        .sort_by_file_name()
-        .into_iter()
-        .filter_map(|entry| read_entry(entry).ok())
-        .collect();
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => Some(raw),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            lock.release()?;
+            return Err(anyhow!(err).context(format!("Failed to read {path}")));
+        }
+    };
+    lock.release()?;
+    raw.map(|raw| serde_json::from_str(&raw).context(format!("Failed to parse {path}")))
+        .transpose()
+}
+
+pub struct ReadSections {
+    pub sections: Vec<(Utf8PathBuf, Section)>,
+    pub errors: Vec<SectionReadError>,
+}
+
+// Files which cannot be read or parsed are reported instead of being skipped, such that a corrupt
+// result does not silently vanish from monitoring. Temporary files left behind by interrupted
+// writes and the lock file are ignored.
+pub fn read(directory: impl AsRef<Path>, locker: &Locker) -> Result<ReadSections, LockerError> {
+    let lock = locker.wait_for_read_lock()?;
+    let mut read_sections = ReadSections {
+        sections: vec![],
+        errors: vec![],
+    };
+    for entry in WalkDir::new(directory).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                read_sections.errors.push(SectionReadError {
+                    path: error
+                        .path()
+                        .and_then(|path| Utf8PathBuf::try_from(path.to_path_buf()).ok()),
+                    error: error.to_string(),
+                });
+                continue;
+            }
+        };
+        if entry.file_type().is_dir()
+            || is_tmp_file(entry.path())
+            || entry.path() == locker.lock_path()
+        {
+            continue;
+        }
+        match read_entry(entry) {
+            Ok((path, Ok(section))) => read_sections.sections.push((path, section)),
+            Ok((path, Err(error))) => read_sections.errors.push(SectionReadError {
+                path: Some(path),
+                error: format!("{error:#}"),
+            }),
+            Err(error) => read_sections.errors.push(SectionReadError {
+                path: None,
+                error: format!("{error:#}"),
+            }),
+        }
+    }
     lock.release()?;
-    Ok(sections)
+    Ok(read_sections)
+}
+
+fn is_tmp_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "tmp")
 }
 
 fn write(section: &Section, path: impl AsRef<Utf8Path>, locker: &Locker) -> Result<(), Terminate> {
@@ -120,8 +261,66 @@ fn write_to_tmp_and_move(
     errors
 }
 
-fn read_entry(entry: Result<DirEntry, walkdir::Error>) -> AnyhowResult<Section> {
-    let entry = entry?;
-    let raw = fs::read_to_string(entry.path())?;
-    Ok(serde_json::from_str(&raw)?)
+// The outer error is only returned if the path is not valid UTF-8.
+fn read_entry(entry: DirEntry) -> AnyhowResult<(Utf8PathBuf, AnyhowResult<Section>)> {
+    let path = Utf8PathBuf::try_from(entry.into_path())?;
+    let section = fs::read_to_string(&path)
+        .context(format!("Failed to read {path}"))
+        .and_then(|raw| serde_json::from_str(&raw).context(format!("Failed to parse {path}")));
+    Ok((path, section))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed_section() {
+        let section = Section::new("name", "content", Host::Source, None);
+        assert_eq!(section.content, "content");
+        assert!(section.compression.is_none());
+    }
+
+    #[test]
+    fn gzip_compressed_section() {
+        let section = Section::new(
+            "name",
+            "content",
+            Host::Source,
+            Some(&SectionCompression::Gzip),
+        );
+        assert_ne!(section.content, "content");
+        assert_eq!(section.compression, Some(SectionCompression::Gzip));
+        assert_eq!(section.decompressed_content().unwrap(), "content");
+    }
+
+    #[test]
+    fn read_reports_corrupt_files_and_ignores_tmp_and_lock_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let results_dir = Utf8PathBuf::try_from(temp_dir.path().to_path_buf()).unwrap();
+        fs::create_dir(results_dir.join("plans")).unwrap();
+        let lock_path = results_dir.join("results.lock");
+        fs::write(&lock_path, "").unwrap();
+        let locker = Locker::new(&lock_path, None);
+        Section::new("name", "content", Host::Source, None)
+            .write(results_dir.join("plans").join("valid.json"), &locker)
+            .unwrap();
+        fs::write(results_dir.join("corrupt.json"), "{\"host\":").unwrap();
+        fs::write(results_dir.join("valid.json.tmp"), "").unwrap();
+
+        let read_sections = read(&results_dir, &locker).unwrap();
+        assert_eq!(
+            read_sections
+                .sections
+                .iter()
+                .map(|(path, section)| (path.clone(), section.content.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(results_dir.join("plans").join("valid.json"), "content")]
+        );
+        assert_eq!(read_sections.errors.len(), 1);
+        assert_eq!(
+            read_sections.errors[0].path,
+            Some(results_dir.join("corrupt.json"))
+        );
+    }
 }
@@ -1,12 +1,14 @@
 use crate::child_process_supervisor::{ChildProcessSupervisor, StdioPaths};
 use crate::command_spec::CommandSpec;
-use crate::config::SessionConfig;
+use crate::config::{ResourceLimitsConfig, SessionConfig, TerminationConfig};
+use crate::results::{ResourceLimit, ResourceUsage, TerminatedBy};
 use crate::tasks::{run_task, TaskSpec};
 use crate::termination::Outcome;
 
-use anyhow::{Context, Result as AnyhowResult};
+use anyhow::{bail, Result as AnyhowResult};
 use camino::{Utf8Path, Utf8PathBuf};
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::process::ExitStatus;
 use tokio_util::sync::CancellationToken;
 
 #[derive(Clone, Eq, Hash, PartialEq, Debug)]
@@ -27,6 +29,10 @@ impl Session {
     }
 
     pub fn run(&self, spec: &RunSpec) -> AnyhowResult<Outcome<i32>> {
+        Ok(self.run_and_report(spec)?.outcome)
+    }
+
+    pub fn run_and_report(&self, spec: &RunSpec) -> AnyhowResult<RunReport> {
         match self {
             Self::Current(current_session) => current_session.run(spec),
             Self::User(user_session) => user_session.run(spec),
@@ -75,30 +81,46 @@ pub struct RunSpec<'a> {
     pub command_spec: &'a CommandSpec,
     pub base_path: &'a Utf8Path,
     pub timeout: u64,
+    pub resource_limits_config: Option<&'a ResourceLimitsConfig>,
+    pub termination_config: Option<&'a TerminationConfig>,
     pub cancellation_token: &'a CancellationToken,
 }
 
+pub struct RunReport {
+    pub outcome: Outcome<i32>,
+    pub resource_usage: Option<ResourceUsage>,
+    pub resource_limit_violation: Option<ResourceLimit>,
+    pub terminated_by: Option<TerminatedBy>,
+}
+
 impl CurrentSession {
-    fn run(&self, spec: &RunSpec) -> AnyhowResult<Outcome<i32>> {
-        match (ChildProcessSupervisor {
+    fn run(&self, spec: &RunSpec) -> AnyhowResult<RunReport> {
+        let child_process_report = ChildProcessSupervisor {
+            id: spec.id,
             command_spec: spec.command_spec,
             stdio_paths: Some(StdioPaths {
                 stdout: Utf8PathBuf::from(format!("{}.stdout", spec.base_path)),
                 stderr: Utf8PathBuf::from(format!("{}.stderr", spec.base_path)),
             }),
             timeout: spec.timeout,
+            resource_limits_config: spec.resource_limits_config,
+            termination_config: spec.termination_config,
             cancellation_token: spec.cancellation_token,
         }
-        .run())?
-        {
-            Outcome::Completed(exit_status) => Ok(Outcome::Completed(
-                exit_status
-                    .code()
-                    .context("Failed to retrieve exit code of subprocess")?,
-            )),
-            Outcome::Timeout => Ok(Outcome::Timeout),
-            Outcome::Cancel => Ok(Outcome::Cancel),
-        }
+        .run()?;
+        Ok(RunReport {
+            outcome: match child_process_report.outcome {
+                Outcome::Completed(exit_status) => Outcome::Completed(exit_code(
+                    &exit_status,
+                    child_process_report.resource_limit_violation.is_some(),
+                )?),
+                Outcome::Timeout => Outcome::Timeout,
+                Outcome::Cancel => Outcome::Cancel,
+            },
+            resource_usage: Some(child_process_report.resource_usage),
+            resource_limit_violation: child_process_report.resource_limit_violation,
+            terminated_by: child_process_report.terminated_by,
+        })
     }
 
     pub fn id(&self) -> String {
@@ -106,15 +128,36 @@ impl CurrentSession {
     }
 }
 
+// A process exceeding its memory limit is killed by the kernel together with its entire cgroup,
+// so it has no exit code. Like shells do, we report such processes as exiting with 128 + signal.
+fn exit_code(exit_status: &ExitStatus, resource_limit_violated: bool) -> AnyhowResult<i32> {
+    if let Some(exit_code) = exit_status.code() {
+        return Ok(exit_code);
+    }
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(exit_status);
+    #[cfg(windows)]
+    let signal = None;
+    match signal {
+        Some(signal) if resource_limit_violated => Ok(128 + signal),
+        _ => bail!("Failed to retrieve exit code of subprocess, exit status: {exit_status}"),
+    }
+}
+
 impl UserSession {
-    fn run(&self, spec: &RunSpec) -> AnyhowResult<Outcome<i32>> {
-        run_task(&TaskSpec {
-            task_name: spec.id,
-            command_spec: spec.command_spec,
-            user_name: &self.user_name,
-            base_path: spec.base_path,
-            timeout: spec.timeout,
-            cancellation_token: spec.cancellation_token,
+    fn run(&self, spec: &RunSpec) -> AnyhowResult<RunReport> {
+        Ok(RunReport {
+            outcome: run_task(&TaskSpec {
+                task_name: spec.id,
+                command_spec: spec.command_spec,
+                user_name: &self.user_name,
+                base_path: spec.base_path,
+                timeout: spec.timeout,
+                cancellation_token: spec.cancellation_token,
+            })?,
+            resource_usage: None,
+            resource_limit_violation: None,
+            terminated_by: None,
         })
     }
 
@@ -139,4 +182,14 @@ mod tests {
             "Session of user some_user"
         )
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_code_of_killed_process() {
+        use std::os::unix::process::ExitStatusExt;
+        let killed = ExitStatus::from_raw(9);
+        assert_eq!(exit_code(&killed, true).unwrap(), 137);
+        assert!(exit_code(&killed, false).is_err());
+        assert_eq!(exit_code(&ExitStatus::from_raw(3 << 8), false).unwrap(), 3);
+    }
 }
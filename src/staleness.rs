@@ -0,0 +1,197 @@
+use crate::config::{Config, ExecutionConfig};
+use crate::results::{
+    plan_results_directory, scheduler_heartbeat_path, SchedulerHeartbeat, StalePlan,
+    StalenessReport, SCHEDULER_HEARTBEAT_INTERVAL,
+};
+use crate::section::Section;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+// Accounts for rebot, which runs after the attempts and has a timeout of its own.
+const PLAN_GRACE_PERIOD: i64 = 120;
+const MISSED_HEARTBEATS_TOLERATED: i64 = 3;
+
+// A plan report is stale if the next report should already have been written, i.e. if it is older
+// than one execution interval plus the maximum runtime of the plan. Plans without any report yet
+// are not considered stale, the scheduler might still be setting up.
+pub fn staleness_report(
+    config: &Config,
+    sections: &[(Utf8PathBuf, Section)],
+    now: i64,
+) -> StalenessReport {
+    let scheduler_heartbeat_age = find_section(
+        sections,
+        &scheduler_heartbeat_path(&config.results_directory),
+    )
+    .and_then(|section| serde_json::from_str::<SchedulerHeartbeat>(&section.content).ok())
+    .map(|heartbeat| now - heartbeat.timestamp);
+    let plan_results_directory = plan_results_directory(&config.results_directory);
+    let mut stale_plans = vec![];
+    for plan_group in &config.plan_groups {
+        for plan_config in &plan_group.plans {
+            let Some(timestamp) = find_section(
+                sections,
+                &plan_results_directory.join(format!("{}.json", plan_config.id)),
+            )
+            .and_then(|section| section.version) else {
+                continue;
+            };
+            let age = now - timestamp;
+            let threshold = plan_staleness_threshold(
+                plan_group.execution_interval,
+                &plan_config.execution_config,
+            );
+            if age > threshold {
+                stale_plans.push(StalePlan {
+                    plan_id: plan_config.id.clone(),
+                    age,
+                    threshold,
+                });
+            }
+        }
+    }
+    StalenessReport {
+        scheduler_heartbeat_age,
+        scheduler_stale: scheduler_heartbeat_age.is_some_and(|age| {
+            age > MISSED_HEARTBEATS_TOLERATED * SCHEDULER_HEARTBEAT_INTERVAL as i64
+        }),
+        stale_plans,
+    }
+}
+
+pub fn plan_staleness_threshold(
+    execution_interval: u64,
+    execution_config: &ExecutionConfig,
+) -> i64 {
+    (execution_interval + execution_config.n_attempts_max as u64 * execution_config.timeout) as i64
+        + PLAN_GRACE_PERIOD
+}
+
+fn find_section<'a>(
+    sections: &'a [(Utf8PathBuf, Section)],
+    path: &Utf8Path,
+) -> Option<&'a Section> {
+    sections
+        .iter()
+        .find(|(section_path, _)| section_path == path)
+        .map(|(_, section)| section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        EnvironmentConfig, ExecutionConfig, PlanConfig, PlanMetadata, RCCConfig, RCCProfileConfig,
+        RetryStrategy, RobotConfig, SequentialPlanGroup, SessionConfig, Source,
+        WorkingDirectoryCleanupConfig,
+    };
+    use crate::section::Host;
+
+    fn plan_config(id: &str) -> PlanConfig {
+        PlanConfig {
+            id: id.into(),
+            source: Source::Manual {
+                base_dir: "/suites".into(),
+            },
+            robot_config: RobotConfig {
+                robot_target: "tasks.robot".into(),
+                top_level_suite_name: None,
+                suites: vec![],
+                tests: vec![],
+                test_tags_include: vec![],
+                test_tags_exclude: vec![],
+                variables: vec![],
+                variable_files: vec![],
+                argument_files: vec![],
+                exit_on_failure: false,
+            },
+            execution_config: ExecutionConfig {
+                n_attempts_max: 2,
+                retry_strategy: RetryStrategy::Complete,
+                timeout: 60,
+                resource_limits_config: None,
+                termination_config: None,
+                orphan_process_policy: None,
+            },
+            environment_config: EnvironmentConfig::System,
+            session_config: SessionConfig::Current,
+            working_directory_cleanup_config: WorkingDirectoryCleanupConfig::MaxExecutions(5),
+            host: Host::Source,
+            section_config: None,
+            metadata: PlanMetadata {
+                application: "app".into(),
+                suite_name: "suite".into(),
+                variant: "".into(),
+            },
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            working_directory: "/working".into(),
+            results_directory: "/results".into(),
+            managed_directory: "/managed".into(),
+            managed_robots_config: None,
+            environment_build_parallelism: None,
+            results_directory_lock_timeout: None,
+            rcc_config: RCCConfig {
+                binary_path: "/bin/rcc".into(),
+                profile_config: RCCProfileConfig::Default,
+            },
+            plan_groups: vec![SequentialPlanGroup {
+                plans: vec![plan_config("fresh"), plan_config("stale")],
+                execution_interval: 300,
+            }],
+        }
+    }
+
+    fn section(content: &str, version: Option<i64>) -> Section {
+        Section {
+            version,
+            ..Section::new("name", content, Host::Source, None)
+        }
+    }
+
+    #[test]
+    fn stale_plans_and_scheduler() {
+        let now = 10000;
+        let sections = vec![
+            (
+                Utf8PathBuf::from("/results/scheduler_heartbeat.json"),
+                section(r#"{"timestamp":9800}"#, None),
+            ),
+            (
+                Utf8PathBuf::from("/results/plans/fresh.json"),
+                section("", Some(9500)),
+            ),
+            (
+                Utf8PathBuf::from("/results/plans/stale.json"),
+                section("", Some(9000)),
+            ),
+        ];
+        assert_eq!(
+            staleness_report(&config(), &sections, now),
+            StalenessReport {
+                scheduler_heartbeat_age: Some(200),
+                scheduler_stale: true,
+                stale_plans: vec![StalePlan {
+                    plan_id: "stale".into(),
+                    age: 1000,
+                    threshold: 540,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_written_yet() {
+        assert_eq!(
+            staleness_report(&config(), &[], 10000),
+            StalenessReport {
+                scheduler_heartbeat_age: None,
+                scheduler_stale: false,
+                stale_plans: vec![],
+            }
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::command_spec::CommandSpec;
+use crate::orphans::RUN_ID_ENV_VAR;
 use crate::tasks::TaskSpec;
 use crate::termination::{kill_process_tree, waited, Outcome};
 
@@ -240,6 +241,7 @@ fn build_task_script(task_name: &str, command_spec: &CommandSpec, paths: &Paths)
     [
         String::from("@echo off"),
         format!("echo Robotmk: running task {task_name}. Please do not close this window."),
+        format!("set \"{RUN_ID_ENV_VAR}={task_name}\""),
         format!("{command_spec} > {} 2> {}", paths.stdout, paths.stderr),
         format!("echo %errorlevel% > {}", paths.exit_code),
     ]
@@ -326,6 +328,7 @@ mod tests {
             ),
             "@echo off
 echo Robotmk: running task robotmk_task. Please do not close this window.
+set \"ROBOTMK_RUN_ID=robotmk_task\"
 \"C:\\\\somewhere\\\\rcc.exe\" \"mandatory\" \"--some-flag\" \"--some-option\" \"some-value\" \
 > C:\\working\\plans\\my_plan\\123\\0.stdout 2> C:\\working\\plans\\my_plan\\123\\0.stderr
 echo %errorlevel% > C:\\working\\plans\\my_plan\\123\\0.exit_code"
@@ -2,10 +2,10 @@ use assert_cmd::cargo::cargo_bin;
 use camino::{Utf8Path, Utf8PathBuf};
 use robotmk::config::{Config, RCCConfig, RCCProfileConfig};
 use robotmk::lock::Locker;
-use robotmk::results::ConfigSection;
+use robotmk::results::{results_directory_lock_path, ConfigSection};
 use robotmk::section::{Host, WritePiggybackSection, WriteSection};
 use serde::Serialize;
-use std::fs::{create_dir, write};
+use std::fs::{create_dir, read_dir, write};
 use std::io;
 use std::process::{Command, Output};
 use tempfile::tempdir;
@@ -18,10 +18,10 @@ fn test_agent_plugin() -> anyhow::Result<()> {
     let results_dir = temp_dir_path.join("results");
     create_dir(&results_dir)?;
 
-    let config = create_config(&results_dir);
+    let config = create_config(&temp_dir_path, &results_dir);
     let config_path = temp_dir_path.join("robotmk.json");
     write(&config_path, serde_json::to_string(&config)?)?;
-    write_results(&results_dir, &config_path)?;
+    write_results(&results_dir)?;
 
     let output = run_agent_plugin(&temp_dir_path)?;
     assert!(output.status.success());
@@ -31,7 +31,13 @@ fn test_agent_plugin() -> anyhow::Result<()> {
         format!(
             "<<<robotmk_config_v2:sep(0)>>>\n{}\n{}",
             serde_json::to_string(&ConfigSection::FileContent(serde_json::to_string(&config)?))?,
-            "<<<section:sep(0)>>>
+            "<<<robotmk_results_reading_status:sep(0)>>>
+\"Ok\"
+<<<robotmk_staleness:sep(0)>>>
+{\"scheduler_heartbeat_age\":null,\"scheduler_stale\":false,\"stale_plans\":[]}
+<<<robotmk_section_read_errors:sep(0)>>>
+[]
+<<<section:sep(0)>>>
 {\"a\":\"a\",\"b\":123}
 <<<<piggy>>>>
 <<<piggyback_section:sep(0)>>>
@@ -44,11 +50,208 @@ fn test_agent_plugin() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn create_config(results_dir: &Utf8Path) -> Config {
+#[test]
+#[ignore]
+fn test_agent_plugin_scheduler_not_started() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let config = create_config(
+        &temp_dir_path.join("working"),
+        &temp_dir_path.join("results"),
+    );
+    write(
+        temp_dir_path.join("robotmk.json"),
+        serde_json::to_string(&config)?,
+    )?;
+
+    let output = run_agent_plugin(&temp_dir_path)?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)?
+        .ends_with("<<<robotmk_results_reading_status:sep(0)>>>\n\"SchedulerNotStarted\"\n"));
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_agent_plugin_incremental() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let results_dir = temp_dir_path.join("results");
+    create_dir(&results_dir)?;
+
+    let config = create_config(&temp_dir_path, &results_dir);
+    let config_path = temp_dir_path.join("robotmk.json");
+    write(&config_path, serde_json::to_string(&config)?)?;
+    write_results(&results_dir)?;
+
+    let state_dir = temp_dir_path.join("state");
+    let incremental = |consumer| -> anyhow::Result<String> {
+        Ok(String::from_utf8(
+            run_agent_plugin_with_args(
+                &temp_dir_path,
+                &[
+                    "--incremental",
+                    consumer,
+                    "--state-directory",
+                    state_dir.as_str(),
+                ],
+            )?
+            .stdout,
+        )?)
+    };
+
+    let first_output = incremental("site")?;
+    assert!(first_output.contains("<<<robotmk_delivery_state_status:sep(0)>>>\n\"Ok\"\n"));
+    assert!(first_output.contains("<<<section:sep(0)>>>"));
+    assert!(first_output.contains("<<<piggyback_section:sep(0)>>>"));
+    assert_eq!(
+        read_dir(state_dir.join("robotmk_delivery_state"))?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?,
+        ["site.json"]
+    );
+
+    let second_output = incremental("site")?;
+    assert!(second_output.contains("<<<section:sep(0)>>>"));
+    assert!(!second_output.contains("<<<piggyback_section:sep(0)>>>"));
+
+    let other_consumer_output = incremental("other_site")?;
+    assert!(other_consumer_output.contains("<<<piggyback_section:sep(0)>>>"));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_agent_plugin_incremental_rejects_path_as_consumer() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let state_dir = temp_dir_path.join("state");
+    for consumer in ["../../site", "sub/site", ".."] {
+        let output = run_agent_plugin_with_args(
+            &temp_dir_path,
+            &[
+                "--incremental",
+                consumer,
+                "--state-directory",
+                state_dir.as_str(),
+            ],
+        )?;
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+    assert!(!state_dir.exists());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_agent_plugin_incremental_without_state_directory() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let results_dir = temp_dir_path.join("results");
+    create_dir(&results_dir)?;
+
+    let config = create_config(&temp_dir_path, &results_dir);
+    write(
+        temp_dir_path.join("robotmk.json"),
+        serde_json::to_string(&config)?,
+    )?;
+    write_results(&results_dir)?;
+
+    for _ in 0..2 {
+        let output = String::from_utf8(
+            run_agent_plugin_with_args(&temp_dir_path, &["--incremental", "site"])?.stdout,
+        )?;
+        assert!(output
+            .contains("<<<robotmk_delivery_state_status:sep(0)>>>\n\"StateDirectoryUnknown\"\n"));
+        assert!(output.contains("<<<piggyback_section:sep(0)>>>"));
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_agent_plugin_json_formats() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let results_dir = temp_dir_path.join("results");
+    create_dir(&results_dir)?;
+
+    let config = create_config(&temp_dir_path, &results_dir);
+    let config_path = temp_dir_path.join("robotmk.json");
+    write(&config_path, serde_json::to_string(&config)?)?;
+    write_results(&results_dir)?;
+
+    let json_output = run_agent_plugin_with_args(&temp_dir_path, &["--format", "json"])?;
+    assert!(json_output.status.success());
+    let document: serde_json::Value = serde_json::from_slice(&json_output.stdout)?;
+    assert_eq!(document["source"].as_array().unwrap().len(), 5);
+    assert_eq!(
+        document["source"][4],
+        serde_json::json!({"name": "section", "content": {"a": "a", "b": 123}, "compression": null})
+    );
+    assert_eq!(
+        document["piggyback"]["piggy"],
+        serde_json::json!([{
+            "name": "piggyback_section",
+            "content": {"x": true, "y": "some-string"},
+            "compression": null,
+        }])
+    );
+
+    let ndjson_output = run_agent_plugin_with_args(&temp_dir_path, &["--format", "ndjson"])?;
+    assert!(ndjson_output.status.success());
+    let lines = String::from_utf8(ndjson_output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[0]["host"], "Source");
+    assert_eq!(lines[0]["name"], "robotmk_config_v2");
+    assert_eq!(
+        lines[5],
+        serde_json::json!({
+            "host": {"Piggyback": "piggy"},
+            "name": "piggyback_section",
+            "content": {"x": true, "y": "some-string"},
+            "compression": null,
+        })
+    );
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_agent_plugin_check_unknown_plan() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let temp_dir_path = Utf8PathBuf::try_from(temp_dir.path().to_path_buf())?;
+    let results_dir = temp_dir_path.join("results");
+    create_dir(&results_dir)?;
+
+    let config = create_config(&temp_dir_path, &results_dir);
+    write(
+        temp_dir_path.join("robotmk.json"),
+        serde_json::to_string(&config)?,
+    )?;
+
+    let output = run_agent_plugin_with_args(&temp_dir_path, &["check", "unknown_plan"])?;
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "UNKNOWN - Plan unknown_plan is not configured\n"
+    );
+    Ok(())
+}
+
+fn create_config(working_dir: &Utf8Path, results_dir: &Utf8Path) -> Config {
     Config {
-        working_directory: "".into(),
+        working_directory: working_dir.into(),
         results_directory: results_dir.into(),
         managed_directory: "".into(),
+        managed_robots_config: None,
+        environment_build_parallelism: None,
+        results_directory_lock_timeout: None,
         rcc_config: RCCConfig {
             binary_path: "".into(),
             profile_config: RCCProfileConfig::Default,
@@ -57,8 +260,10 @@ fn create_config(results_dir: &Utf8Path) -> Config {
     }
 }
 
-fn write_results(results_dir: &Utf8Path, config_path: &Utf8Path) -> anyhow::Result<()> {
-    let locker = Locker::new(config_path, None);
+fn write_results(results_dir: &Utf8Path) -> anyhow::Result<()> {
+    let lock_path = results_directory_lock_path(results_dir);
+    write(&lock_path, "")?;
+    let locker = Locker::new(&lock_path, None);
     let sub_dir = results_dir.join("sub");
     create_dir(&sub_dir)?;
     Section {
@@ -100,10 +305,22 @@ impl WritePiggybackSection for PiggybackSection {
     fn name() -> &'static str {
         "piggyback_section"
     }
+
+    fn version(&self) -> Option<i64> {
+        Some(1)
+    }
 }
 
 fn run_agent_plugin(config_dir: &Utf8Path) -> io::Result<Output> {
+    run_agent_plugin_with_args(config_dir, &[])
+}
+
+fn run_agent_plugin_with_args(config_dir: &Utf8Path, args: &[&str]) -> io::Result<Output> {
     let mut agent_plugin_cmd = Command::new(cargo_bin("robotmk_agent_plugin"));
-    agent_plugin_cmd.env("MK_CONFDIR", config_dir);
+    agent_plugin_cmd
+        .env("MK_CONFDIR", config_dir)
+        .env_remove("MK_VARDIR")
+        .env_remove("MK_STATEDIR")
+        .args(args);
     agent_plugin_cmd.output()
 }
@@ -2,10 +2,14 @@ use anyhow::Result as AnyhowResult;
 use camino::Utf8PathBuf;
 use robotmk::config::RetryStrategy;
 use robotmk::environment::{Environment, SystemEnvironment};
-use robotmk::plans::run_attempts_with_rebot;
+use robotmk::plans::{run_attempts_with_rebot, AttemptsSpec};
 use robotmk::results::AttemptOutcome;
+#[cfg(unix)]
+use robotmk::rf::output::TIMED_OUT_TAG;
 use robotmk::rf::robot::Robot;
 use robotmk::session::{CurrentSession, Session};
+#[cfg(unix)]
+use std::fs::read_to_string;
 use tempfile::tempdir;
 use tokio_util::sync::CancellationToken;
 
@@ -19,15 +23,18 @@ fn test_rebot_run() -> AnyhowResult<()> {
         command_line_args: vec![],
         retry_strategy: RetryStrategy::Complete,
     };
-    let (attempt_reports, rebot) = run_attempts_with_rebot(
-        &robot,
-        "test",
-        &Environment::System(SystemEnvironment {}),
-        &Session::Current(CurrentSession {}),
-        3,
-        &CancellationToken::default(),
-        &test_dir,
-    )?;
+    let (attempt_reports, rebot) = run_attempts_with_rebot(&AttemptsSpec {
+        id: "test",
+        robot: &robot,
+        environment: &Environment::System(SystemEnvironment {}),
+        session: &Session::Current(CurrentSession {}),
+        timeout: 3,
+        resource_limits_config: None,
+        termination_config: None,
+        orphan_process_policy: None,
+        cancellation_token: &CancellationToken::default(),
+        output_directory: &test_dir,
+    })?;
     assert_eq!(attempt_reports.len(), 1);
     let attempt_report = &attempt_reports[0];
     assert_eq!(attempt_report.index, 1);
@@ -47,21 +54,71 @@ fn test_timeout_process() -> AnyhowResult<()> {
         command_line_args: vec!["--variable".into(), format!("RESOURCE:{resource}")],
         retry_strategy: RetryStrategy::Complete,
     };
-    let (attempt_reports, rebot) = run_attempts_with_rebot(
-        &robot,
-        "test",
-        &Environment::System(SystemEnvironment {}),
-        &Session::Current(CurrentSession {}),
-        1,
-        &CancellationToken::default(),
-        &test_dir,
-    )?;
+    let (attempt_reports, rebot) = run_attempts_with_rebot(&AttemptsSpec {
+        id: "test",
+        robot: &robot,
+        environment: &Environment::System(SystemEnvironment {}),
+        session: &Session::Current(CurrentSession {}),
+        timeout: 1,
+        resource_limits_config: None,
+        termination_config: None,
+        orphan_process_policy: None,
+        cancellation_token: &CancellationToken::default(),
+        output_directory: &test_dir,
+    })?;
     assert_eq!(attempt_reports.len(), 1);
     let attempt_report = &attempt_reports[0];
     assert_eq!(attempt_report.index, 1);
     assert_eq!(attempt_report.outcome, AttemptOutcome::TimedOut);
-    assert!(rebot.is_none());
+    // On Unix, Robot Framework is interrupted gracefully and writes a partial output. On Windows,
+    // the process tree is killed.
     #[cfg(unix)]
-    assert!(!resource.is_file());
+    {
+        assert!(rebot.is_some());
+        assert_eq!(attempt_report.timed_out_tests, vec!["Tasks.Spawn Child"]);
+        assert!(read_to_string(test_dir.join("rebot.xml"))?.contains(TIMED_OUT_TAG));
+        assert!(!resource.is_file());
+    }
+    #[cfg(windows)]
+    assert!(rebot.is_none());
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore]
+fn test_memory_limit_exceeded() -> AnyhowResult<()> {
+    use robotmk::config::ResourceLimitsConfig;
+    use robotmk::results::ResourceLimit;
+
+    robotmk::cgroup::prepare_hierarchy()?;
+    let test_dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+    let robot = Robot {
+        robot_target: "tests/memory_hog/tasks.robot".into(),
+        n_attempts_max: 1,
+        command_line_args: vec![],
+        retry_strategy: RetryStrategy::Complete,
+    };
+    let (attempt_reports, _) = run_attempts_with_rebot(&AttemptsSpec {
+        id: "test_memory_limit_exceeded",
+        robot: &robot,
+        environment: &Environment::System(SystemEnvironment {}),
+        session: &Session::Current(CurrentSession {}),
+        timeout: 30,
+        resource_limits_config: Some(&ResourceLimitsConfig {
+            memory_max: Some(256 * 1024 * 1024),
+            cpu_quota_percent: None,
+            pids_max: None,
+        }),
+        termination_config: None,
+        orphan_process_policy: None,
+        cancellation_token: &CancellationToken::default(),
+        output_directory: &test_dir,
+    })?;
+    assert_eq!(attempt_reports.len(), 1);
+    assert_eq!(
+        attempt_reports[0].outcome,
+        AttemptOutcome::ResourceLimitExceeded(ResourceLimit::Memory)
+    );
     Ok(())
 }
@@ -145,6 +145,9 @@ fn create_config(
         working_directory: test_dir.join("working"),
         results_directory: test_dir.join("results"),
         managed_directory: test_dir.join("managed_robots"),
+        managed_robots_config: None,
+        environment_build_parallelism: Some(2),
+        results_directory_lock_timeout: None,
         rcc_config,
         plan_groups: vec![
             SequentialPlanGroup {
@@ -170,6 +173,9 @@ fn create_config(
                             n_attempts_max: 1,
                             retry_strategy: RetryStrategy::Complete,
                             timeout: 10,
+                            resource_limits_config: None,
+                            termination_config: None,
+                            orphan_process_policy: None,
                         },
                         environment_config: EnvironmentConfig::Rcc(RCCEnvironmentConfig {
                             robot_yaml_path: "robot.yaml".into(),
@@ -179,6 +185,7 @@ fn create_config(
                         working_directory_cleanup_config:
                             WorkingDirectoryCleanupConfig::MaxExecutions(4),
                         host: Host::Source,
+                        section_config: None,
                         metadata: PlanMetadata {
                             application: "app1".into(),
                             suite_name: "minimal_suite".into(),
@@ -207,6 +214,9 @@ fn create_config(
                             n_attempts_max: 1,
                             retry_strategy: RetryStrategy::Complete,
                             timeout: 15,
+                            resource_limits_config: None,
+                            termination_config: None,
+                            orphan_process_policy: None,
                         },
                         environment_config: EnvironmentConfig::Rcc(RCCEnvironmentConfig {
                             robot_yaml_path: "robot.yaml".into(),
@@ -219,6 +229,7 @@ fn create_config(
                             120,
                         ),
                         host: Host::Source,
+                        section_config: None,
                         metadata: PlanMetadata {
                             application: "app2".into(),
                             suite_name: "minimal_suite".into(),
@@ -228,7 +239,9 @@ fn create_config(
                     PlanConfig {
                         id: "managed_robot".into(),
                         source: Source::Managed {
-                            tar_gz_path: managed_robot_archive_path.into(),
+                            archive_path: managed_robot_archive_path.into(),
+                            verification_config: None,
+                            size_limit: None,
                         },
                         robot_config: RobotConfig {
                             robot_target: "tasks.robot".into(),
@@ -246,6 +259,9 @@ fn create_config(
                             n_attempts_max: 1,
                             retry_strategy: RetryStrategy::Complete,
                             timeout: 15,
+                            resource_limits_config: None,
+                            termination_config: None,
+                            orphan_process_policy: None,
                         },
                         environment_config: EnvironmentConfig::Rcc(RCCEnvironmentConfig {
                             robot_yaml_path: "robot.yaml".into(),
@@ -261,6 +277,7 @@ fn create_config(
                             120,
                         ),
                         host: Host::Source,
+                        section_config: None,
                         metadata: PlanMetadata {
                             application: "managed".into(),
                             suite_name: "robot_archive".into(),
@@ -295,6 +312,9 @@ fn create_config(
                         n_attempts_max: 1,
                         retry_strategy: RetryStrategy::Complete,
                         timeout: 17,
+                        resource_limits_config: None,
+                        termination_config: None,
+                        orphan_process_policy: None,
                     },
                     environment_config: EnvironmentConfig::System,
                     session_config: SessionConfig::Current,
@@ -302,6 +322,7 @@ fn create_config(
                         4,
                     ),
                     host: Host::Piggyback("oink".into()),
+                    section_config: None,
                     metadata: PlanMetadata {
                         application: "app3".into(),
                         suite_name: "minimal_suite".into(),
@@ -380,7 +401,12 @@ async fn assert_working_directory(
     assert!(working_directory.is_dir());
     assert_eq!(
         directory_entries(working_directory, 1),
-        ["environment_building", "plans", "rcc_setup"]
+        [
+            "environment_build_cache",
+            "environment_building",
+            "plans",
+            "rcc_setup"
+        ]
     );
     assert_eq!(
         directory_entries(working_directory.join("rcc_setup"), 2),
@@ -535,12 +561,16 @@ fn assert_results_directory(results_directory: &Utf8Path) {
         directory_entries(results_directory, 2),
         [
             "environment_build_states.json",
+            "managed_robots.json",
             "plans",
             "plans/managed_robot.json",
             "plans/no_rcc.json",
             #[cfg(windows)]
             "plans/rcc_headed.json",
             "plans/rcc_headless.json",
+            "results.lock",
+            "scheduler_health.json",
+            "scheduler_heartbeat.json",
             "scheduler_phase.json",
             "setup_failures.json"
         ]